@@ -0,0 +1,256 @@
+use crate::logger::expand_path;
+
+use rb_sys::{Qfalse, Qtrue, VALUE};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StackStats {
+    pub count: u64,
+    // wall time since each sample's previous sample of its thread
+    pub wall_nanos: u64,
+    // CPU time of the thread since its previous sample
+    pub cpu_nanos: u64,
+}
+
+struct AggregatorWriter {
+    sender: Sender<Vec<(String, StackStats)>>,
+    thread: JoinHandle<()>,
+}
+
+// Aggregates samples into folded stacks instead of writing every sample.
+// The scanner adds stacks of iseq addresses without the GVL, they are folded into frame names
+// with the symbols of consume_iseq_buffer at the end of each epoch, as an address means nothing after GC.
+// Epochs end at GC and at least every second, the folded stacks are written to the file
+// at the first epoch end after flush_interval, one line per distinct stack:
+//   thread-<native thread id>;<root frame>;...;<current frame> <samples count> <wall time in micros> <cpu time in micros>
+pub struct Aggregator {
+    // thread id, iseq addresses from the current frame => stats of the current epoch
+    epoch_stacks: HashMap<Vec<u64>, StackStats>,
+    epoch_symbols: HashMap<u64, String>,
+    folded: HashMap<String, StackStats>,
+    flush_interval: Duration,
+    flushed_at: Instant,
+    writer: Option<AggregatorWriter>,
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Aggregator {
+            epoch_stacks: HashMap::new(),
+            epoch_symbols: HashMap::new(),
+            folded: HashMap::new(),
+            flush_interval: Duration::ZERO,
+            flushed_at: Instant::now(),
+            writer: None,
+        }
+    }
+
+    // Starts aggregating, the file is rewritten with all stacks every flush_interval
+    pub fn open(&mut self, path: &str, flush_interval: Duration) -> std::io::Result<()> {
+        self.close();
+
+        let path = expand_path(path);
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("sdb-aggregator".to_string())
+            .spawn(move || writer_loop(path, receiver))?;
+
+        self.writer = Some(AggregatorWriter { sender, thread });
+        self.epoch_stacks.clear();
+        self.epoch_symbols.clear();
+        self.folded.clear();
+        self.flush_interval = flush_interval;
+        self.flushed_at = Instant::now();
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    // stack is the thread id followed by iseq addresses, from the current frame to the root one
+    #[inline]
+    pub fn add(&mut self, stack: &[u64], wall_nanos: u64, cpu_nanos: u64) {
+        // the key is only allocated for a new stack
+        if let Some(stats) = self.epoch_stacks.get_mut(stack) {
+            stats.count += 1;
+            stats.wall_nanos += wall_nanos;
+            stats.cpu_nanos += cpu_nanos;
+            return;
+        }
+
+        self.epoch_stacks.insert(
+            stack.to_vec(),
+            StackStats {
+                count: 1,
+                wall_nanos,
+                cpu_nanos,
+            },
+        );
+    }
+
+    #[inline]
+    pub fn add_symbol(&mut self, iseq_addr: u64, label: &str, path: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.epoch_symbols
+            .insert(iseq_addr, frame_name(label, path));
+    }
+
+    // Folds the stacks of the epoch, it is called after all their symbols are added
+    pub fn end_epoch(&mut self) {
+        for (stack, stats) in self.epoch_stacks.drain() {
+            let mut line = format!("thread-{}", stack[0]);
+            for iseq_addr in stack[1..].iter().rev() {
+                // non-iseq frames, such as ifunc, have no symbols
+                if let Some(name) = self.epoch_symbols.get(iseq_addr) {
+                    line.push(';');
+                    line.push_str(name);
+                }
+            }
+
+            let folded = self.folded.entry(line).or_default();
+            folded.count += stats.count;
+            folded.wall_nanos += stats.wall_nanos;
+            folded.cpu_nanos += stats.cpu_nanos;
+        }
+        self.epoch_symbols.clear();
+
+        if self.is_enabled() && self.flushed_at.elapsed() >= self.flush_interval {
+            self.flush();
+        }
+    }
+
+    // Hands over all stacks to the writer, writing them doesn't block the scanner or GC
+    pub fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_ref() {
+            let stacks = self
+                .folded
+                .iter()
+                .map(|(line, stats)| (line.clone(), *stats))
+                .collect();
+            let _ = writer.sender.send(stacks);
+        }
+
+        self.flushed_at = Instant::now();
+    }
+
+    // Writes out the stacks folded so far and stops the writer thread
+    pub fn close(&mut self) {
+        if self.writer.is_none() {
+            return;
+        }
+
+        self.flush();
+
+        if let Some(writer) = self.writer.take() {
+            drop(writer.sender);
+
+            match writer.thread.join() {
+                Ok(_) => log::info!(
+                    "[{}][aggregator] stacks={}, samples={}",
+                    std::process::id(),
+                    self.folded.len(),
+                    self.folded.values().map(|stats| stats.count).sum::<u64>()
+                ),
+                Err(_) => log::error!("[aggregator] writer thread panicked"),
+            }
+        }
+    }
+
+    // In a forked child the writer thread doesn't exist, the parent's stacks are its own
+    pub fn abandon_after_fork(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            std::mem::forget(writer);
+        }
+
+        self.epoch_stacks.clear();
+        self.epoch_symbols.clear();
+        self.folded.clear();
+    }
+}
+
+// the same frame names as sdb-decode's folded stacks
+fn frame_name(label: &str, path: &str) -> String {
+    let name = if path.is_empty() {
+        label.to_string()
+    } else {
+        format!("{} ({})", label, path)
+    };
+
+    name.replace(';', ":")
+}
+
+// The file is replaced at once, so readers never see a partial file
+fn writer_loop(path: String, receiver: Receiver<Vec<(String, StackStats)>>) {
+    let tmp_path = format!("{}.tmp", path);
+
+    for mut stacks in receiver {
+        stacks.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let result = File::create(&tmp_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            for (line, stats) in &stacks {
+                writeln!(
+                    writer,
+                    "{} {} {} {}",
+                    line,
+                    stats.count,
+                    stats.wall_nanos / 1000,
+                    stats.cpu_nanos / 1000
+                )?;
+            }
+            writer.flush()?;
+
+            std::fs::rename(&tmp_path, &path)
+        });
+
+        if let Err(e) = result {
+            log::error!("[aggregator] write {} failed: {:?}", path, e);
+        }
+    }
+}
+
+// path nil means sdb-<pid>.folded, samples are aggregated instead of written until open_logger
+pub unsafe extern "C" fn rb_open_aggregator(
+    _module: VALUE,
+    path: VALUE,
+    flush_interval: VALUE,
+) -> VALUE {
+    let path = if rb_sys::NIL_P(path) {
+        None
+    } else {
+        crate::stack_scanner::RUBY_API.ruby_str_to_rust_str(rb_sys::rb_obj_as_string(path))
+    };
+    let path = path.unwrap_or(format!("sdb-{}.folded", std::process::id()));
+    let flush_interval = Duration::from_secs_f64(rb_sys::rb_num2dbl(flush_interval));
+
+    let mut stack_scanner = crate::stack_scanner::STACK_SCANNER.lock();
+    stack_scanner.logger_mut().close();
+
+    match stack_scanner.aggregator_mut().open(&path, flush_interval) {
+        Ok(_) => {
+            log::info!(
+                "[{}][aggregator] writes folded stacks to {} every {:?}",
+                std::process::id(),
+                path,
+                flush_interval
+            );
+            Qtrue as VALUE
+        }
+        Err(e) => {
+            log::error!("[aggregator] open {} failed: {:?}", path, e);
+            Qfalse as VALUE
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::logger::{Logger, SAMPLE_HEADER_LEN};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+// label, path and first_lineno of an iseq
+pub type Symbol = (String, String, u32);
+
+// Symbols and lines of captured samples taken in ended epochs, by the snapshot ids
+// which replaced their iseq addresses. They are logged with the symbols of the epoch
+// the samples are logged in, as the addresses may have been reused by then.
+#[derive(Default)]
+pub struct SymbolSnapshot {
+    pub symbols: HashMap<u64, Symbol>,
+    // (snapshot id, pc) => lineno
+    pub lines: HashMap<(u64, u64), u32>,
+}
+
+impl SymbolSnapshot {
+    pub fn append(&mut self, other: SymbolSnapshot) {
+        self.symbols.extend(other.symbols);
+        self.lines.extend(other.lines);
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+        self.lines.clear();
+    }
+}
+
+// Slow request capture holds samples of each request in its thread's ring buffer,
+// they are only logged when the request takes threshold or longer, or ends with a 5xx status.
+// Samples outside requests are not taken.
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureConfig {
+    pub threshold: Duration,
+    // the latest max_samples samples of a request are kept
+    pub max_samples: usize,
+}
+
+impl CaptureConfig {
+    #[inline]
+    pub fn should_keep(&self, duration: Duration, status: Option<i64>) -> bool {
+        duration >= self.threshold || status.map_or(false, |status| status >= 500)
+    }
+}
+
+// Samples have the same layout as the logger's buffer without separators,
+// thread_id, ts, trace_seq, tag_set_seq, thread_state, cpu_nanos, wall_nanos and then the frames.
+pub struct CaptureBuffer {
+    words: VecDeque<u64>,
+    // words count of each sample, the oldest one first
+    sample_lens: VecDeque<usize>,
+    max_samples: usize,
+    // samples overwritten by newer ones
+    overwritten: u64,
+    // the newest samples taken in the current epoch, which still have iseq addresses
+    epoch_samples: usize,
+    snapshot: SymbolSnapshot,
+}
+
+impl CaptureBuffer {
+    pub fn new(max_samples: usize) -> Self {
+        CaptureBuffer {
+            words: VecDeque::new(),
+            sample_lens: VecDeque::new(),
+            max_samples: max_samples.max(1),
+            overwritten: 0,
+            epoch_samples: 0,
+            snapshot: SymbolSnapshot::default(),
+        }
+    }
+
+    #[inline]
+    pub fn push_sample(&mut self, sample: &[u64]) {
+        if self.sample_lens.len() == self.max_samples {
+            if let Some(len) = self.sample_lens.pop_front() {
+                self.words.drain(..len);
+                self.overwritten += 1;
+            }
+        }
+
+        self.words.extend(sample);
+        self.sample_lens.push_back(sample.len());
+        self.epoch_samples = (self.epoch_samples + 1).min(self.sample_lens.len());
+    }
+
+    // Replaces the iseq addresses of the samples taken in the ending epoch with snapshot ids.
+    // snapshot_frame returns the id of an iseq address, with its symbol and the lineno of the pc
+    // when they are known. words of samples with pcs are iseq_addr and pc pairs.
+    pub fn snapshot_epoch<'a>(
+        &mut self,
+        with_pcs: bool,
+        mut snapshot_frame: impl FnMut(u64, u64) -> (u64, Option<&'a Symbol>, Option<u32>),
+    ) {
+        let snapshotted = self.sample_lens.len() - self.epoch_samples;
+        let mut start: usize = self.sample_lens.iter().take(snapshotted).sum();
+        let step = if with_pcs { 2 } else { 1 };
+
+        for len in self.sample_lens.iter().skip(snapshotted) {
+            for i in (start + SAMPLE_HEADER_LEN..start + len).step_by(step) {
+                let pc = if with_pcs { self.words[i + 1] } else { 0 };
+                let (id, symbol, lineno) = snapshot_frame(self.words[i], pc);
+                self.words[i] = id;
+
+                if let Some(symbol) = symbol {
+                    self.snapshot
+                        .symbols
+                        .entry(id)
+                        .or_insert_with(|| symbol.clone());
+                }
+                if let Some(lineno) = lineno {
+                    self.snapshot.lines.insert((id, pc), lineno);
+                }
+            }
+            start += len;
+        }
+
+        self.epoch_samples = 0;
+    }
+
+    // Hands over the samples to the logger, the oldest one first,
+    // and their snapshot symbols to be logged at the end of the epoch.
+    // Samples the logger drops are counted as kept samples dropped.
+    pub fn write_to(self, logger: &mut Logger, snapshot: &mut SymbolSnapshot) {
+        let total_dropped = logger.total_dropped();
+        let mut words = self.words.iter();
+        for len in &self.sample_lens {
+            for word in words.by_ref().take(*len) {
+                logger.push(*word);
+            }
+            logger.push_seperator();
+        }
+        logger.count_kept_dropped(logger.total_dropped() - total_dropped);
+
+        snapshot.append(self.snapshot);
+
+        if self.overwritten > 0 {
+            log::debug!(
+                "[capture] {} samples of the request were overwritten",
+                self.overwritten
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use libc::clockid_t;
+
+// from linux/posix-timers.h
+#[cfg(target_os = "linux")]
+const CPUCLOCK_PERTHREAD_MASK: clockid_t = 4;
+#[cfg(target_os = "linux")]
+const CPUCLOCK_SCHED: clockid_t = 2;
+
+// The CPU clock of a native thread of this process, the same clock pthread_getcpuclockid returns.
+// The scanner only has the native thread id from Thread#native_thread_id, not the pthread_t,
+// so the clock id is made of it the same way glibc does.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread_cpu_clock(thread_id: u64) -> Option<clockid_t> {
+    if thread_id == 0 {
+        return None;
+    }
+
+    Some((!(thread_id as clockid_t) << 3) | CPUCLOCK_PERTHREAD_MASK | CPUCLOCK_SCHED)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn thread_cpu_clock(_thread_id: u64) -> Option<clockid_t> {
+    None
+}
+
+// The CPU time the thread has used, 0 when it is unknown, such as after the thread exited
+#[inline]
+pub(crate) fn read_cpu_nanos(clock: Option<clockid_t>) -> u64 {
+    let clock = match clock {
+        Some(clock) => clock,
+        None => return 0,
+    };
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        return 0;
+    }
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
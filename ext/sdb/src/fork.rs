@@ -0,0 +1,59 @@
+use crate::stack_scanner::{StackScanner, STACK_SCANNER, START_TO_PULL_COND_VAR};
+
+use rb_sys::{Qtrue, VALUE};
+
+use std::cell::RefCell;
+use std::sync::{MutexGuard, Once};
+
+type ForkGuards = (
+    spin::MutexGuard<'static, StackScanner>,
+    MutexGuard<'static, bool>,
+);
+
+static FORK_HOOKS_INIT: Once = Once::new();
+
+thread_local! {
+    // the locks taken before fork, they are released by the same thread in the parent and the child
+    static FORK_GUARDS: RefCell<Option<ForkGuards>> = RefCell::new(None);
+}
+
+// The scanner thread may hold the locks when another thread forks,
+// and it doesn't exist in the child to release them. Holding the locks across fork
+// makes sure the child gets them unlocked and the scanner state isn't half updated.
+// The locks are taken in the same order as gc_enter_callback.
+extern "C" fn prepare_fork() {
+    let stack_scanner = STACK_SCANNER.lock();
+    let start = START_TO_PULL_COND_VAR.0.lock().unwrap();
+
+    FORK_GUARDS.with(|guards| *guards.borrow_mut() = Some((stack_scanner, start)));
+}
+
+extern "C" fn after_fork_parent() {
+    FORK_GUARDS.with(|guards| drop(guards.borrow_mut().take()));
+}
+
+extern "C" fn after_fork_child() {
+    FORK_GUARDS.with(|guards| {
+        if let Some((mut stack_scanner, mut start)) = guards.borrow_mut().take() {
+            stack_scanner.after_fork();
+            *start = true;
+        }
+    });
+}
+
+// It is registered once, the child resets the scanner and Sdb starts scanning again in it,
+// see Sdb.forked!. Forks without Process._fork, such as from C extensions, only reset the scanner.
+pub(crate) unsafe extern "C" fn rb_setup_fork_hooks(_module: VALUE) -> VALUE {
+    FORK_HOOKS_INIT.call_once(|| {
+        let result = libc::pthread_atfork(
+            Some(prepare_fork),
+            Some(after_fork_parent),
+            Some(after_fork_child),
+        );
+        if result != 0 {
+            log::error!("[fork] pthread_atfork failed: {}", result);
+        }
+    });
+
+    Qtrue as VALUE
+}
@@ -1,24 +1,299 @@
-use libc::{pthread_self, pthread_t};
-use rb_sys::{rb_ll2inum, RTypedData, VALUE};
-use rbspy_ruby_structs::ruby_3_1_5::{rb_global_vm_lock_t, rb_thread_t};
+use crate::stack_scanner::RUBY_API;
+use crate::thread_events::{
+    current_native_thread_id, RUBY_INTERNAL_THREAD_EVENT_READY, RUBY_INTERNAL_THREAD_EVENT_RESUMED,
+    RUBY_INTERNAL_THREAD_EVENT_SUSPENDED,
+};
 
+use libc::{c_void, pthread_self, pthread_t};
+use rb_sys::{rb_ary_new, rb_ary_push, rb_float_new, rb_ll2inum, Qnil, RTypedData, VALUE};
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+// Logs the address of the GVL's native mutex of the thread's ractor, lock events of sdb-shim
+// with this lock_addr are GVL acquisitions and releases.
 pub(crate) unsafe extern "C" fn rb_log_gvl_addr(_module: VALUE, thread_val: VALUE) -> VALUE {
     // todo: handle logger initialization
     let thread_ptr: *mut RTypedData = thread_val as *mut RTypedData;
-    let rb_thread_ptr = (*thread_ptr).data as *mut rb_thread_t;
+    let rb_thread_addr = (*thread_ptr).data as u64;
 
-    // access gvl_addr through offset directly
-    let gvl_addr = (*rb_thread_ptr).ractor as u64 + 344;
-    let gvl_ref = gvl_addr as *mut rb_global_vm_lock_t;
-    let lock_addr = &((*gvl_ref).lock) as *const _ as u64;
+    let lock_addr = RUBY_API.get_gvl_lock_addr(thread_val);
+    let owner_addr = RUBY_API.get_gvl_owner(thread_val);
     let tid: pthread_t = pthread_self();
 
     log::info!(
-        "[lock] thread_id={}, rb_thread_addr={}, gvl_mutex_addr={}",
+        "[lock] thread_id={}, rb_thread_addr={}, gvl_mutex_addr={}, gvl_owner_addr={}",
         tid,
-        rb_thread_ptr as u64,
-        lock_addr
+        rb_thread_addr,
+        lock_addr,
+        owner_addr
     );
 
     rb_ll2inum(lock_addr as i64) as VALUE
 }
+
+// GVL time of a thread, or of a request since it began
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GvlTimes {
+    // waited for the GVL, ready to run
+    pub wait_nanos: u64,
+    // ran with the GVL
+    pub run_nanos: u64,
+}
+
+impl GvlTimes {
+    pub const ZERO: GvlTimes = GvlTimes {
+        wait_nanos: 0,
+        run_nanos: 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub(crate) enum GvlState {
+    // no event has come yet
+    Unknown = 0,
+    Waiting = 1,
+    Running = 2,
+    Suspended = 3,
+}
+
+impl GvlState {
+    #[inline]
+    pub(crate) fn from_u8(state: u8) -> Self {
+        match state {
+            1 => GvlState::Waiting,
+            2 => GvlState::Running,
+            3 => GvlState::Suspended,
+            _ => GvlState::Unknown,
+        }
+    }
+}
+
+struct ThreadGvl {
+    state: Cell<GvlState>,
+    since_nanos: Cell<u64>,
+    total: Cell<GvlTimes>,
+    // total when the current request began
+    request_begin: Cell<GvlTimes>,
+    // (index, native thread id) of the slot the state is published in,
+    // the index is GVL_SLOTS_COUNT when no slot was free
+    slot: Cell<Option<(usize, u64)>>,
+}
+
+// The scanner classifies a thread's samples by its GVL state, which the thread's hook publishes
+// in a slot without taking a lock. A thread claims a free slot at its first GVL event and frees it
+// when it exits, threads beyond GVL_SLOTS_COUNT are classified without it.
+struct GvlSlot {
+    // the owner, 0 when the slot is free
+    thread_id: AtomicU64,
+    state: AtomicU8,
+}
+
+const GVL_SLOTS_COUNT: usize = 4096;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_GVL_SLOT: GvlSlot = GvlSlot {
+    thread_id: AtomicU64::new(0),
+    state: AtomicU8::new(GvlState::Unknown as u8),
+};
+
+static GVL_SLOTS: [GvlSlot; GVL_SLOTS_COUNT] = [FREE_GVL_SLOT; GVL_SLOTS_COUNT];
+// slots below it have been claimed, the scanner looks for threads among them
+static GVL_SLOTS_CLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+static GVL_HOOKS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // only the thread itself updates and reads it, GVL events are emitted on the thread
+    static THREAD_GVL: ThreadGvl = const {
+        ThreadGvl {
+            state: Cell::new(GvlState::Unknown),
+            since_nanos: Cell::new(0),
+            total: Cell::new(GvlTimes::ZERO),
+            request_begin: Cell::new(GvlTimes::ZERO),
+            slot: Cell::new(None),
+        }
+    };
+}
+
+#[inline]
+fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// READY: the thread waits for the GVL, RESUMED: it got the GVL, SUSPENDED: it released the GVL.
+// It runs for every GVL switch, so it only reads the clock and updates the thread local.
+pub(crate) unsafe extern "C" fn gvl_event_callback(
+    event: u32,
+    _event_data: *const c_void,
+    _user_data: *mut c_void,
+) {
+    let now = monotonic_nanos();
+
+    THREAD_GVL.with(|gvl| {
+        let elapsed = now.saturating_sub(gvl.since_nanos.get());
+        let mut total = gvl.total.get();
+
+        let state = if event & RUBY_INTERNAL_THREAD_EVENT_READY != 0 {
+            GvlState::Waiting
+        } else if event & RUBY_INTERNAL_THREAD_EVENT_RESUMED != 0 {
+            if gvl.state.get() == GvlState::Waiting {
+                total.wait_nanos += elapsed;
+            }
+            GvlState::Running
+        } else if event & RUBY_INTERNAL_THREAD_EVENT_SUSPENDED != 0 {
+            if gvl.state.get() == GvlState::Running {
+                total.run_nanos += elapsed;
+            }
+            GvlState::Suspended
+        } else {
+            return;
+        };
+
+        gvl.state.set(state);
+        gvl.since_nanos.set(now);
+        gvl.total.set(total);
+        share_state(gvl, state);
+    });
+}
+
+// The slot is claimed at the thread's first event, and again after fork, which frees all slots.
+// Later events only store the state.
+#[inline]
+fn share_state(gvl: &ThreadGvl, state: GvlState) {
+    if let Some((index, thread_id)) = gvl.slot.get() {
+        match GVL_SLOTS.get(index) {
+            Some(slot) if slot.thread_id.load(Ordering::Acquire) == thread_id => {
+                slot.state.store(state as u8, Ordering::Release);
+                return;
+            }
+            Some(_) => {}
+            None => return,
+        }
+    }
+
+    gvl.slot
+        .set(Some(claim_gvl_slot(state).unwrap_or((GVL_SLOTS_COUNT, 0))));
+}
+
+fn claim_gvl_slot(state: GvlState) -> Option<(usize, u64)> {
+    let thread_id = current_native_thread_id()?;
+
+    for (index, slot) in GVL_SLOTS.iter().enumerate() {
+        if slot
+            .thread_id
+            .compare_exchange(0, thread_id, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.state.store(state as u8, Ordering::Release);
+            GVL_SLOTS_CLAIMED.fetch_max(index + 1, Ordering::AcqRel);
+            return Some((index, thread_id));
+        }
+    }
+
+    None
+}
+
+// It is called by the exiting thread, the native thread id may be reused by a new thread
+pub(crate) fn free_gvl_slot() {
+    let _ = THREAD_GVL.try_with(|gvl| {
+        if let Some((index, thread_id)) = gvl.slot.take() {
+            if let Some(slot) = GVL_SLOTS.get(index) {
+                slot.state.store(GvlState::Unknown as u8, Ordering::Release);
+                let _ = slot.thread_id.compare_exchange(
+                    thread_id,
+                    0,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+    });
+}
+
+// In a forked child only the forking thread is left, it claims a slot again at its next GVL event
+pub(crate) fn free_gvl_slots_after_fork() {
+    let claimed = GVL_SLOTS_CLAIMED.swap(0, Ordering::AcqRel);
+    for slot in &GVL_SLOTS[..claimed] {
+        slot.state.store(GvlState::Unknown as u8, Ordering::Release);
+        slot.thread_id.store(0, Ordering::Release);
+    }
+}
+
+// The GVL state published by the thread's hook, Unknown when it has no slot.
+// index is where the thread's slot was found last time, it is updated when the slot has moved.
+#[inline]
+pub(crate) fn load_gvl_state(thread_id: u64, index: &mut usize) -> GvlState {
+    let slot = match GVL_SLOTS.get(*index) {
+        Some(slot) if slot.thread_id.load(Ordering::Acquire) == thread_id => slot,
+        _ => {
+            let claimed = GVL_SLOTS_CLAIMED.load(Ordering::Acquire);
+            match GVL_SLOTS[..claimed]
+                .iter()
+                .position(|slot| slot.thread_id.load(Ordering::Acquire) == thread_id)
+            {
+                Some(found) => {
+                    *index = found;
+                    &GVL_SLOTS[found]
+                }
+                None => return GvlState::Unknown,
+            }
+        }
+    };
+
+    GvlState::from_u8(slot.state.load(Ordering::Acquire))
+}
+
+pub(crate) fn enable_gvl_hooks() {
+    GVL_HOOKS_ENABLED.store(true, Ordering::Release);
+}
+
+#[inline]
+pub(crate) fn gvl_hooks_enabled() -> bool {
+    GVL_HOOKS_ENABLED.load(Ordering::Acquire)
+}
+
+// GVL times of the current thread so far, including the running period in progress,
+// it is called with the GVL
+pub(crate) fn current_thread_gvl_times() -> GvlTimes {
+    THREAD_GVL.with(|gvl| {
+        let mut total = gvl.total.get();
+        if gvl.state.get() == GvlState::Running {
+            total.run_nanos += monotonic_nanos().saturating_sub(gvl.since_nanos.get());
+        }
+        total
+    })
+}
+
+// Returns GVL times of the current thread since the previous call,
+// the request ending on the thread, and starts counting for the next one
+pub(crate) fn restart_request_gvl_times() -> GvlTimes {
+    let total = current_thread_gvl_times();
+
+    THREAD_GVL.with(|gvl| {
+        let begin = gvl.request_begin.replace(total);
+        GvlTimes {
+            wait_nanos: total.wait_nanos.saturating_sub(begin.wait_nanos),
+            run_nanos: total.run_nanos.saturating_sub(begin.run_nanos),
+        }
+    })
+}
+
+// Returns [wait, run] seconds of the current thread, nil without thread event hooks
+pub(crate) unsafe extern "C" fn rb_gvl_times(_module: VALUE) -> VALUE {
+    if !gvl_hooks_enabled() {
+        return Qnil as VALUE;
+    }
+
+    let times = current_thread_gvl_times();
+    let array = rb_ary_new();
+    rb_ary_push(array, rb_float_new(times.wait_nanos as f64 / 1e9));
+    rb_ary_push(array, rb_float_new(times.run_nanos as f64 / 1e9));
+    array
+}
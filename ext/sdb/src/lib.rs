@@ -1,9 +1,16 @@
+mod aggregator;
+mod capture;
+mod cpu_clock;
+mod fork;
 mod gvl;
 mod helpers;
 mod logger;
 mod ruby_version;
+mod sample_format;
 mod stack_scanner;
 mod tester;
+mod thread_events;
+mod thread_state;
 
 use libc::c_char;
 use rb_sys::{
@@ -11,12 +18,15 @@ use rb_sys::{
     VALUE,
 };
 
+use aggregator::*;
+use fork::*;
 use gvl::*;
 use helpers::*;
 use logger::*;
 use stack_scanner::*;
 use std::os::raw::c_void;
 use tester::*;
+use thread_events::*;
 
 use lazy_static::lazy_static;
 
@@ -31,6 +41,7 @@ extern "C" fn gc_enter_callback(_trace_point: VALUE, _data: *mut c_void) {
     stack_scanner.pause();
     stack_scanner.consume_iseq_buffer();
     stack_scanner.mark_iseqs();
+    stack_scanner.mark_threads();
 
     let (lock, _) = &*START_TO_PULL_COND_VAR;
     let mut start = lock.lock().unwrap();
@@ -120,6 +131,18 @@ extern "C" fn Init_sdb() {
                 2,
             );
         };
+        ($module:expr, $name:expr, $callback:expr, 3) => {
+            let transmuted_callback = std::mem::transmute::<
+                unsafe extern "C" fn(VALUE, VALUE, VALUE, VALUE) -> VALUE,
+                unsafe extern "C" fn() -> VALUE,
+            >($callback);
+            rb_define_singleton_method(
+                $module,
+                format!("{}\0", $name).as_ptr() as _,
+                Some(transmuted_callback),
+                3,
+            );
+        };
     }
 
     unsafe {
@@ -146,7 +169,9 @@ extern "C" fn Init_sdb() {
             rb_base_label_from_iseq_addr,
             1
         );
-        define_ruby_method!(module, "init_logger", rb_init_logger, 0);
+        define_ruby_method!(module, "open_logger", rb_open_logger, 1);
+        define_ruby_method!(module, "set_rotation", rb_set_rotation, 3);
+        define_ruby_method!(module, "open_aggregator", rb_open_aggregator, 2);
         define_ruby_method!(module, "log", rb_log, 1);
         define_ruby_method!(
             module,
@@ -158,15 +183,34 @@ extern "C" fn Init_sdb() {
             module,
             "update_threads_to_scan",
             rb_update_threads_to_scan,
-            1
+            2
         );
         define_ruby_method!(module, "stop_scanner", rb_stop_scanner, 0);
+        define_ruby_method!(module, "reset_scanner", rb_reset_scanner, 0);
+        define_ruby_method!(module, "pause", rb_pause_scanner, 0);
+        define_ruby_method!(module, "resume", rb_resume_scanner, 0);
+        define_ruby_method!(module, "set_sleep_interval", rb_set_sleep_interval, 1);
+        define_ruby_method!(module, "set_record_lines", rb_set_record_lines, 1);
+        define_ruby_method!(module, "begin_request", rb_begin_request, 1);
+        define_ruby_method!(module, "finish_request", rb_finish_request, 1);
+        define_ruby_method!(module, "set_capture", rb_set_capture, 2);
+        define_ruby_method!(module, "tag", rb_tag, 2);
+        define_ruby_method!(module, "untag", rb_untag, 1);
+        define_ruby_method!(module, "clear_tags", rb_clear_tags, 1);
         define_ruby_method!(module, "setup_gc_hooks", setup_gc_hooks, 0);
+        define_ruby_method!(module, "setup_thread_hooks", rb_setup_thread_hooks, 0);
+        define_ruby_method!(module, "setup_fork_hooks", rb_setup_fork_hooks, 0);
+        define_ruby_method!(module, "gvl_times", rb_gvl_times, 0);
 
         let sdb_tester = rb_define_module("SdbTester\0".as_ptr() as *const c_char);
         define_ruby_method!(sdb_tester, "ec_from_thread", rb_get_ec_from_thread, 1);
         define_ruby_method!(sdb_tester, "iseqs_from_ec", rb_get_iseqs, 1);
         define_ruby_method!(sdb_tester, "is_iseq_imemo", rb_is_iseq_imemo, 1);
         define_ruby_method!(sdb_tester, "iseq_info", rb_get_iseq_info, 1);
+        define_ruby_method!(sdb_tester, "frames_from_ec", rb_get_frames, 1);
+        define_ruby_method!(sdb_tester, "lineno", rb_get_lineno, 2);
+        define_ruby_method!(sdb_tester, "method_entry_info", rb_get_method_entry_info, 1);
+        define_ruby_method!(sdb_tester, "frame_labels", rb_get_frame_labels, 1);
+        define_ruby_method!(sdb_tester, "gvl_info", rb_get_gvl_info, 1);
     }
 }
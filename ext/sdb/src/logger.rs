@@ -1,64 +1,469 @@
+use crate::gvl::GvlTimes;
+use crate::helpers::internal_id;
+use crate::sample_format::{ClockSource, RecordWriter, SampleHeader, SampleWriter, TextWriter};
+
+use chrono::Utc;
 use fast_log::config::Config;
-use rb_sys::{Qtrue, VALUE};
+use rb_sys::{Qfalse, Qnil, Qtrue, VALUE};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 const FAST_LOG_CHAN_LEN: usize = 100_000;
 const ISEQS_BUFFER_SIZE: usize = 1_000_000;
+// buffers owned by the writer thread or waiting in the free list, besides the current one
+const SPARE_BUFFERS_COUNT: usize = 2;
+const SEPARATOR: u64 = u64::MAX;
+// thread_id, ts, trace_seq, tag_set_seq, thread_state, cpu_nanos and wall_nanos before the frames of a sample
+pub(crate) const SAMPLE_HEADER_LEN: usize = 7;
+// samples are dropped when the collector hasn't taken this many bytes
+const STREAM_MAX_PENDING_BYTES: usize = 8 * 1024 * 1024;
+const STREAM_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+static FAST_LOG_INIT: Once = Once::new();
+static SAMPLE_FILES_COUNT: AtomicUsize = AtomicUsize::new(0);
+// set by Sdb.continuous_mode
+static ROTATION: Mutex<Option<Rotation>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // see sample_format.rs for the layout
+    Binary,
+    // the legacy sdb.log lines
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sink {
+    File,
+    // a collector listening on a Unix domain socket
+    Unix,
+    Stdout,
+}
+
+// set by Sdb.init_logger
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    // the file's or the socket's path, %p is replaced by the pid.
+    // Files are sdb-<pid>.bin in the current directory when it is None.
+    pub path: Option<String>,
+    pub format: OutputFormat,
+    pub sink: Sink,
+    // only for the file sink
+    pub rotation: Option<Rotation>,
+}
+
+// Continuous mode rotates the sample file when it reaches max_bytes or max_age,
+// and only keeps the latest keep files of the process.
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+    pub keep: usize,
+}
+
+enum WriterMessage {
+    Samples {
+        buffer: Vec<u64>,
+        len: usize,
+        dropped: u64,
+        with_pcs: bool,
+    },
+    Symbol {
+        iseq_addr: u64,
+        label: String,
+        path: String,
+        first_lineno: u32,
+    },
+    Line {
+        iseq_addr: u64,
+        pc: u64,
+        lineno: u32,
+    },
+    TraceBegin {
+        trace_seq: u64,
+        thread_id: u64,
+        ts: u64,
+        trace_id: String,
+    },
+    TraceEnd {
+        trace_seq: u64,
+        ts: u64,
+        gvl: GvlTimes,
+    },
+    TagSet {
+        tag_set_seq: u64,
+        tags: Vec<(String, String)>,
+    },
+    Flush,
+    // symbols and lines of the samples before it have been sent,
+    // the output is rotated or reconnected with reopen, see Logger::end_epoch
+    EpochEnd {
+        reopen: bool,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WriterTotals {
+    pub samples: u64,
+    pub symbols: u64,
+    pub lines: u64,
+    pub files: u64,
+    // samples dropped as the collector falls behind or is gone
+    pub stream_dropped: u64,
+}
+
+struct WriterHandle {
+    sender: Sender<WriterMessage>,
+    free_buffers: Receiver<Vec<u64>>,
+    // set by the writer when the file should be rotated or the collector reconnected
+    reopen_due: Arc<AtomicBool>,
+    thread: JoinHandle<WriterTotals>,
+}
 
+// The scanner fills the current buffer and hands it over to the writer thread when it is full.
+// Buffers are only handed over at sample boundaries, an in-progress sample is moved to the next buffer.
+// When the writer falls behind and no free buffer is left, the in-progress sample is dropped and counted,
+// so is a sample longer than the buffer, and so are the samples of an epoch flushed without a free buffer.
 pub struct Logger {
-    buffer: [u64; ISEQS_BUFFER_SIZE],
-    buffer_size: usize,
+    buffer: Vec<u64>,
     buffer_index: usize,
+    // where the in-progress sample starts
+    sample_start: usize,
+    // the in-progress sample has been dropped, ignore its items until the separator
+    dropping: bool,
+    dropped: u64,
+    total_dropped: u64,
+    // samples of kept requests among the dropped ones, see CaptureBuffer::write_to
+    kept_dropped: u64,
+    // a frame is an iseq address and pc pair instead of an iseq address
+    with_pcs: bool,
+    writer: Option<WriterHandle>,
 }
 
 impl Logger {
     pub fn new() -> Self {
+        Self::with_buffer_size(ISEQS_BUFFER_SIZE)
+    }
+
+    // buffer_size is in words, a sample longer than it is dropped
+    pub fn with_buffer_size(buffer_size: usize) -> Self {
         Logger {
-            buffer: [0; ISEQS_BUFFER_SIZE],
-            buffer_size: ISEQS_BUFFER_SIZE,
+            buffer: vec![0; buffer_size],
             buffer_index: 0,
+            sample_start: 0,
+            dropping: false,
+            dropped: 0,
+            total_dropped: 0,
+            kept_dropped: 0,
+            with_pcs: false,
+            writer: None,
+        }
+    }
+
+    // fast_log can only be initialized once, the scanner may be started again after it is stopped,
+    // so the first log_path is used.
+    pub fn init(log_path: &str) {
+        FAST_LOG_INIT.call_once(|| {
+            let config = Config::new().file(&expand_path(log_path));
+            fast_log::init(config.chan_len(Some(FAST_LOG_CHAN_LEN))).unwrap();
+        });
+    }
+
+    // Samples and symbols go to a per-process file by default, see OutputConfig for the others.
+    // Returns the path of the file or the socket.
+    pub fn open_output(
+        &mut self,
+        config: OutputConfig,
+        ruby_version: &str,
+    ) -> std::io::Result<String> {
+        self.close();
+
+        let output = SampleOutput::open(config, ruby_version)?;
+        let path = output.path.clone();
+
+        let (sender, receiver) = mpsc::channel();
+        let (free_sender, free_buffers) = mpsc::channel();
+        for _ in 0..SPARE_BUFFERS_COUNT {
+            free_sender.send(vec![0; self.buffer.len()]).unwrap();
         }
+        let reopen_due = Arc::new(AtomicBool::new(false));
+        let writer_reopen_due = reopen_due.clone();
+
+        let thread = thread::Builder::new()
+            .name("sdb-writer".to_string())
+            .spawn(move || writer_loop(output, receiver, free_sender, writer_reopen_due))?;
+
+        self.writer = Some(WriterHandle {
+            sender,
+            free_buffers,
+            reopen_due,
+            thread,
+        });
+        self.buffer_index = 0;
+        self.sample_start = 0;
+        self.dropping = false;
+        self.dropped = 0;
+        self.total_dropped = 0;
+        self.kept_dropped = 0;
+
+        Ok(path)
     }
 
-    pub fn init() -> &'static fast_log::Logger {
-        fast_log::init(
-            Config::new()
-                .file("sdb.log")
-                .chan_len(Some(FAST_LOG_CHAN_LEN)),
-        )
-        .unwrap()
+    // It should be set before scanning, as samples in a buffer must have the same layout.
+    pub fn set_with_pcs(&mut self, with_pcs: bool) {
+        self.with_pcs = with_pcs;
     }
 
     #[inline]
     pub fn push(&mut self, item: u64) {
-        if self.buffer_index < self.buffer_size {
-            self.buffer[self.buffer_index] = item;
-            self.buffer_index += 1;
-        } else {
-            log::info!("[{}][stack_frames]{:?}", std::process::id(), self.buffer);
-            self.buffer_index = 0;
+        if self.dropping {
+            return;
+        }
+
+        // the in-progress sample can't be moved to the next buffer when it fills this one
+        if self.buffer_index == self.buffer.len()
+            && (self.sample_start == 0 || !self.swap_buffer(false))
+        {
+            self.dropping = true;
+            self.buffer_index = self.sample_start;
+            return;
         }
+
+        self.buffer[self.buffer_index] = item;
+        self.buffer_index += 1;
     }
 
     #[inline]
     pub fn push_seperator(&mut self) {
-        self.push(u64::MAX);
-        self.push(u64::MAX);
+        self.push(SEPARATOR);
+        self.push(SEPARATOR);
+
+        if self.dropping {
+            self.dropping = false;
+            self.dropped += 1;
+            self.total_dropped += 1;
+        }
+
+        self.sample_start = self.buffer_index;
     }
 
+    // Hands over all complete samples to the writer and asks it to flush the file.
+    // It is called between samples, so there is no in-progress sample.
+    // It never waits for the writer, it is called under the GVL, such as at GC.
     #[inline]
     pub fn flush(&mut self) {
-        log::info!(
-            "[{}][stack_frames]{:?}",
-            std::process::id(),
-            &self.buffer[..self.buffer_index]
-        );
-        self.buffer_index = 0;
+        self.hand_over_samples(false);
+
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::Flush);
+        }
+    }
+
+    // Hands over the samples of the ending epoch before its symbols, the writer flushes
+    // the output at the epoch end. It doesn't wait for the writer, see hand_over_samples.
+    #[inline]
+    pub fn end_epoch_samples(&mut self) {
+        self.hand_over_samples(false);
+    }
+
+    // The samples must go before the symbols of their epoch, so when no free buffer is left
+    // they are dropped and counted instead of waiting for one. Only close waits,
+    // as it waits for the writer to finish anyway.
+    fn hand_over_samples(&mut self, wait: bool) {
+        if (self.sample_start == 0 && self.dropped == 0) || self.swap_buffer(wait) {
+            return;
+        }
+
+        let count = count_samples(&self.buffer[..self.sample_start]);
+        self.dropped += count;
+        self.total_dropped += count;
+        self.buffer
+            .copy_within(self.sample_start..self.buffer_index, 0);
+        self.buffer_index -= self.sample_start;
+        self.sample_start = 0;
+    }
+
+    // Sends complete samples to the writer and continues the in-progress sample in a free buffer.
+    // Returns false when no free buffer is available, or when the writer is gone with wait.
+    fn swap_buffer(&mut self, wait: bool) -> bool {
+        let writer = match self.writer.as_ref() {
+            Some(writer) => writer,
+            None => return false,
+        };
+
+        let buffer = if wait {
+            writer.free_buffers.recv().ok()
+        } else {
+            writer.free_buffers.try_recv().ok()
+        };
+        let mut buffer = match buffer {
+            Some(buffer) => buffer,
+            None => return false,
+        };
+
+        let in_progress = self.buffer_index - self.sample_start;
+        buffer[..in_progress].copy_from_slice(&self.buffer[self.sample_start..self.buffer_index]);
+        std::mem::swap(&mut self.buffer, &mut buffer);
+
+        let _ = writer.sender.send(WriterMessage::Samples {
+            buffer,
+            len: self.sample_start,
+            dropped: self.dropped,
+            with_pcs: self.with_pcs,
+        });
+
+        self.dropped = 0;
+        self.buffer_index = in_progress;
+        self.sample_start = 0;
+
+        true
+    }
+
+    #[inline]
+    pub fn total_dropped(&self) -> u64 {
+        self.total_dropped
+    }
+
+    // Samples of a request kept by slow request capture are written at its end,
+    // they are dropped like others when no buffer is free, and reported apart on close.
+    #[inline]
+    pub fn count_kept_dropped(&mut self, count: u64) {
+        self.kept_dropped += count;
+    }
+
+    // Stops the writer thread after it writes everything it has received, and reports totals.
+    pub fn close(&mut self) {
+        if self.writer.is_none() {
+            return;
+        }
+
+        // the samples left are dropped when the writer thread is gone, such as it panicked
+        self.hand_over_samples(true);
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::Flush);
+        }
         log::logger().flush();
+
+        if let Some(writer) = self.writer.take() {
+            drop(writer.sender);
+
+            match writer.thread.join() {
+                Ok(totals) => log::info!(
+                    "[{}][logger] samples={}, dropped={}, kept_dropped={}, stream_dropped={}, symbols={}, lines={}, files={}",
+                    std::process::id(),
+                    totals.samples,
+                    self.total_dropped,
+                    self.kept_dropped,
+                    totals.stream_dropped,
+                    totals.symbols,
+                    totals.lines,
+                    totals.files
+                ),
+                Err(_) => log::error!("[logger] writer thread panicked"),
+            }
+        }
+    }
+
+    // In a forked child the writer thread doesn't exist, so its handle is leaked instead of joined,
+    // and the parent's samples in the buffer are dropped, the parent writes them.
+    pub fn abandon_after_fork(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            std::mem::forget(writer);
+        }
+
+        self.buffer_index = 0;
+        self.sample_start = 0;
+        self.dropping = false;
+        self.dropped = 0;
+        self.total_dropped = 0;
+        self.kept_dropped = 0;
+    }
+
+    // The writer checks the output after each buffer, the file is rotated or the collector
+    // is reconnected at the next epoch end, as an output must have the symbols of its samples.
+    // The scanner ends an epoch soon when it is due.
+    #[inline]
+    pub fn is_reopen_due(&self) -> bool {
+        self.writer
+            .as_ref()
+            .map_or(false, |writer| writer.reopen_due.load(Ordering::Acquire))
+    }
+
+    // Returns true when the output is reopened after the symbols and lines of the epoch,
+    // lines are translated only once, so the scanner translates them again for the new output.
+    #[inline]
+    pub fn end_epoch(&mut self) -> bool {
+        let mut reopen = false;
+        if let Some(writer) = self.writer.as_ref() {
+            reopen = writer.reopen_due.swap(false, Ordering::AcqRel);
+            let _ = writer.sender.send(WriterMessage::EpochEnd { reopen });
+        }
+
+        reopen
+    }
+
+    #[inline]
+    pub fn log_symbol(&mut self, iseq_addr: u64, label: &str, path: &str, first_lineno: u32) {
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::Symbol {
+                iseq_addr,
+                label: label.to_string(),
+                path: path.to_string(),
+                first_lineno,
+            });
+        }
+    }
+
+    // Samples of the thread are tagged with trace_seq until the request ends
+    #[inline]
+    pub fn log_trace_begin(&mut self, trace_seq: u64, thread_id: u64, ts: u64, trace_id: &str) {
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::TraceBegin {
+                trace_seq,
+                thread_id,
+                ts,
+                trace_id: trace_id.to_string(),
+            });
+        }
+    }
+
+    #[inline]
+    pub fn log_trace_end(&mut self, trace_seq: u64, ts: u64, gvl: GvlTimes) {
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer
+                .sender
+                .send(WriterMessage::TraceEnd { trace_seq, ts, gvl });
+        }
+    }
+
+    // Samples with tag_set_seq have these tags
+    #[inline]
+    pub fn log_tag_set(&mut self, tag_set_seq: u64, tags: &[(String, String)]) {
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::TagSet {
+                tag_set_seq,
+                tags: tags.to_vec(),
+            });
+        }
     }
 
     #[inline]
-    pub fn log_symbol(str: &str) {
-        log::info!("[{}][symbol]{}", std::process::id(), str);
+    pub fn log_line(&mut self, iseq_addr: u64, pc: u64, lineno: u32) {
+        if let Some(writer) = self.writer.as_ref() {
+            let _ = writer.sender.send(WriterMessage::Line {
+                iseq_addr,
+                pc,
+                lineno,
+            });
+        }
     }
 
     #[inline]
@@ -67,9 +472,617 @@ impl Logger {
     }
 }
 
-pub unsafe extern "C" fn rb_init_logger(_module: VALUE) -> VALUE {
-    Logger::init();
-    return Qtrue as VALUE;
+struct SampleOutput {
+    writer: Box<dyn RecordWriter + Send>,
+    // the current file's or the socket's path
+    path: String,
+    // OutputConfig's path, rotated files are named after it
+    base_path: Option<String>,
+    format: OutputFormat,
+    opened_at: Instant,
+    ruby_version: String,
+    rotation: Option<Rotation>,
+    // requests in progress, trace_seq => (thread_id, begin_ts, trace_id),
+    // they are written again to the next file or connection
+    traces: BTreeMap<u64, (u64, u64, String)>,
+    // all tag sets, tag_set_seq => tags
+    tag_sets: BTreeMap<u64, Vec<(String, String)>>,
+    // rotated files, the oldest one first
+    rotated_paths: VecDeque<String>,
+    // only for the unix sink
+    stream_status: Option<Arc<StreamStatus>>,
+    // samples dropped since the last dropped record
+    stream_dropped: u64,
+}
+
+impl SampleOutput {
+    fn open(config: OutputConfig, ruby_version: &str) -> std::io::Result<Self> {
+        let path = match config.sink {
+            Sink::File if config.rotation.is_some() => {
+                rotated_sample_file_path(config.path.as_deref())
+            }
+            Sink::File => match config.path.as_deref() {
+                Some(path) => expand_path(path),
+                None => sample_file_path(),
+            },
+            Sink::Unix => match config.path.as_deref() {
+                Some(path) => expand_path(path),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "the unix sink needs the socket's path",
+                    ))
+                }
+            },
+            Sink::Stdout => "-".to_string(),
+        };
+
+        let mut stream_status = None;
+        let inner: Box<dyn Write + Send> = match config.sink {
+            Sink::File => Box::new(BufWriter::new(File::create(&path)?)),
+            Sink::Unix => {
+                let sink = UnixStreamSink::connect(&path);
+                stream_status = Some(sink.status.clone());
+                Box::new(sink)
+            }
+            Sink::Stdout => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        let rotation = if config.sink == Sink::File {
+            config.rotation
+        } else {
+            None
+        };
+
+        Ok(SampleOutput {
+            writer: create_record_writer(inner, config.format, ruby_version)?,
+            path,
+            base_path: config.path,
+            format: config.format,
+            opened_at: Instant::now(),
+            ruby_version: ruby_version.to_string(),
+            rotation,
+            traces: BTreeMap::new(),
+            tag_sets: BTreeMap::new(),
+            rotated_paths: VecDeque::new(),
+            stream_status,
+            stream_dropped: 0,
+        })
+    }
+
+    fn write_trace(
+        &mut self,
+        trace_seq: u64,
+        thread_id: u64,
+        ts: u64,
+        trace_id: String,
+    ) -> std::io::Result<()> {
+        let result = self.writer.write_trace(trace_seq, thread_id, ts, &trace_id);
+        self.traces.insert(trace_seq, (thread_id, ts, trace_id));
+        result
+    }
+
+    fn write_trace_end(&mut self, trace_seq: u64, ts: u64, gvl: GvlTimes) -> std::io::Result<()> {
+        self.traces.remove(&trace_seq);
+        self.writer
+            .write_trace_end(trace_seq, ts, gvl.wait_nanos, gvl.run_nanos)
+    }
+
+    fn write_tag_set(
+        &mut self,
+        tag_set_seq: u64,
+        tags: Vec<(String, String)>,
+    ) -> std::io::Result<()> {
+        let result = self.writer.write_tag_set(tag_set_seq, &tags);
+        self.tag_sets.insert(tag_set_seq, tags);
+        result
+    }
+
+    // The collector is gone or falls behind, samples should be dropped
+    #[inline]
+    fn is_congested(&self) -> bool {
+        match self.stream_status.as_ref() {
+            Some(status) => {
+                !status.connected.load(Ordering::Relaxed)
+                    || status.pending_bytes.load(Ordering::Relaxed) >= STREAM_MAX_PENDING_BYTES
+            }
+            None => false,
+        }
+    }
+
+    fn write_samples(
+        &mut self,
+        words: &[u64],
+        dropped: u64,
+        with_pcs: bool,
+        totals: &mut WriterTotals,
+    ) -> std::io::Result<()> {
+        if self.is_congested() {
+            let count = count_samples(words);
+            self.stream_dropped += count + dropped;
+            totals.stream_dropped += count;
+            return Ok(());
+        }
+
+        let dropped = dropped + std::mem::take(&mut self.stream_dropped);
+        if dropped > 0 {
+            self.writer.write_dropped(dropped)?;
+        }
+        write_samples(self.writer.as_mut(), words, with_pcs, totals)?;
+
+        // sends them to the collector without blocking
+        if self.stream_status.is_some() {
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // The file is due for rotation by its size or age,
+    // or the collector is gone and it is time to connect again
+    fn is_reopen_due(&mut self) -> std::io::Result<bool> {
+        if let Some(status) = self.stream_status.as_ref() {
+            return Ok(!status.connected.load(Ordering::Relaxed)
+                && self.opened_at.elapsed() >= STREAM_RECONNECT_INTERVAL);
+        }
+
+        let rotation = match self.rotation.as_ref() {
+            Some(rotation) => rotation,
+            None => return Ok(false),
+        };
+        if self.opened_at.elapsed() >= rotation.max_age {
+            return Ok(true);
+        }
+
+        self.writer.flush()?;
+        let size = std::fs::metadata(&self.path)?.len();
+        Ok(size >= rotation.max_bytes)
+    }
+
+    // Rotates the file or reconnects to the collector with reopen, returns true when a new file is opened
+    fn end_epoch(&mut self, reopen: bool) -> std::io::Result<bool> {
+        if self.stream_status.is_some() {
+            // sends the epoch to the collector without blocking
+            self.writer.flush()?;
+            if reopen {
+                self.reconnect()?;
+            }
+            return Ok(false);
+        }
+
+        if reopen {
+            return self.rotate();
+        }
+
+        self.writer.flush()?;
+        Ok(false)
+    }
+
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        let sink = UnixStreamSink::connect(&self.path);
+        self.stream_status = Some(sink.status.clone());
+        self.opened_at = Instant::now();
+
+        let mut writer = create_record_writer(Box::new(sink), self.format, &self.ruby_version)?;
+        write_kept_records(writer.as_mut(), &self.traces, &self.tag_sets)?;
+        self.writer = writer;
+
+        Ok(())
+    }
+
+    // Returns true when it is rotated
+    fn rotate(&mut self) -> std::io::Result<bool> {
+        let keep = match self.rotation.as_ref() {
+            Some(rotation) => rotation.keep,
+            None => return Ok(false),
+        };
+        self.writer.flush()?;
+
+        let path = rotated_sample_file_path(self.base_path.as_deref());
+        let file = Box::new(BufWriter::new(File::create(&path)?));
+        let mut writer = create_record_writer(file, self.format, &self.ruby_version)?;
+        write_kept_records(writer.as_mut(), &self.traces, &self.tag_sets)?;
+
+        self.writer = writer;
+        self.opened_at = Instant::now();
+        self.rotated_paths
+            .push_back(std::mem::replace(&mut self.path, path));
+
+        // the current file is counted
+        while self.rotated_paths.len() + 1 > keep.max(1) {
+            if let Some(oldest) = self.rotated_paths.pop_front() {
+                if let Err(e) = std::fs::remove_file(&oldest) {
+                    log::error!("[logger] remove sample file {} failed: {:?}", oldest, e);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+struct StreamStatus {
+    connected: AtomicBool,
+    pending_bytes: AtomicUsize,
+}
+
+// Streams records to a collector over a nonblocking Unix domain socket.
+// Bytes the socket doesn't take right away are kept in pending and sent at the next flush,
+// the writer drops samples instead of blocking when too many bytes are pending.
+// A write error means the collector is gone, the sink discards everything until reconnected.
+struct UnixStreamSink {
+    stream: Option<UnixStream>,
+    pending: Vec<u8>,
+    // shared with SampleOutput, as the sink is behind the record writer
+    status: Arc<StreamStatus>,
+}
+
+impl UnixStreamSink {
+    fn connect(path: &str) -> Self {
+        let stream = UnixStream::connect(path).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        });
+
+        let stream = match stream {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                log::error!("[logger] connect collector {} failed: {:?}", path, e);
+                None
+            }
+        };
+
+        let status = StreamStatus::default();
+        status.connected.store(stream.is_some(), Ordering::Relaxed);
+
+        UnixStreamSink {
+            stream,
+            pending: Vec::new(),
+            status: Arc::new(status),
+        }
+    }
+
+    fn disconnect(&mut self, e: std::io::Error) {
+        log::error!("[logger] collector disconnected: {:?}", e);
+        self.stream = None;
+        self.pending.clear();
+        self.status.connected.store(false, Ordering::Relaxed);
+        self.status.pending_bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Write for UnixStreamSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.stream.is_some() {
+            self.pending.extend_from_slice(buf);
+            self.status
+                .pending_bytes
+                .store(self.pending.len(), Ordering::Relaxed);
+        }
+
+        Ok(buf.len())
+    }
+
+    // sends as many bytes as the socket takes without blocking
+    fn flush(&mut self) -> std::io::Result<()> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        let mut sent = 0;
+        let mut error = None;
+        while sent < self.pending.len() {
+            match stream.write(&self.pending[sent..]) {
+                Ok(0) => {
+                    error = Some(std::io::ErrorKind::WriteZero.into());
+                    break;
+                }
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        match error {
+            Some(e) => self.disconnect(e),
+            None => {
+                self.pending.drain(..sent);
+                self.status
+                    .pending_bytes
+                    .store(self.pending.len(), Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn create_record_writer(
+    inner: Box<dyn Write + Send>,
+    format: OutputFormat,
+    ruby_version: &str,
+) -> std::io::Result<Box<dyn RecordWriter + Send>> {
+    let mut writer: Box<dyn RecordWriter + Send> = match format {
+        OutputFormat::Binary => Box::new(SampleWriter::new(inner)),
+        OutputFormat::Text => Box::new(TextWriter::new(inner)),
+    };
+    writer.write_header(
+        ClockSource::RealtimeMicros,
+        std::process::id(),
+        ruby_version,
+    )?;
+    writer.flush()?;
+
+    Ok(writer)
+}
+
+// A new file or connection must be decodable on its own
+fn write_kept_records(
+    writer: &mut dyn RecordWriter,
+    traces: &BTreeMap<u64, (u64, u64, String)>,
+    tag_sets: &BTreeMap<u64, Vec<(String, String)>>,
+) -> std::io::Result<()> {
+    for (trace_seq, (thread_id, ts, trace_id)) in traces {
+        writer.write_trace(*trace_seq, *thread_id, *ts, trace_id)?;
+    }
+
+    for (tag_set_seq, tags) in tag_sets {
+        writer.write_tag_set(*tag_set_seq, tags)?;
+    }
+
+    Ok(())
+}
+
+fn writer_loop(
+    mut file: SampleOutput,
+    receiver: Receiver<WriterMessage>,
+    free_sender: Sender<Vec<u64>>,
+    reopen_due: Arc<AtomicBool>,
+) -> WriterTotals {
+    let mut totals = WriterTotals {
+        files: 1,
+        ..Default::default()
+    };
+
+    for message in receiver {
+        // the output is checked after each buffer and epoch
+        let check_reopen = matches!(
+            message,
+            WriterMessage::Samples { .. } | WriterMessage::EpochEnd { .. }
+        );
+
+        let result = match message {
+            WriterMessage::Samples {
+                buffer,
+                len,
+                dropped,
+                with_pcs,
+            } => {
+                let result = file.write_samples(&buffer[..len], dropped, with_pcs, &mut totals);
+
+                // the logger may have been closed already, the buffer is freed then
+                let _ = free_sender.send(buffer);
+                result
+            }
+            WriterMessage::Symbol {
+                iseq_addr,
+                label,
+                path,
+                first_lineno,
+            } => {
+                totals.symbols += 1;
+                file.writer
+                    .write_symbol(iseq_addr, &label, &path, first_lineno)
+            }
+            WriterMessage::Line {
+                iseq_addr,
+                pc,
+                lineno,
+            } => {
+                totals.lines += 1;
+                file.writer.write_line(iseq_addr, pc, lineno)
+            }
+            WriterMessage::TraceBegin {
+                trace_seq,
+                thread_id,
+                ts,
+                trace_id,
+            } => file.write_trace(trace_seq, thread_id, ts, trace_id),
+            WriterMessage::TraceEnd { trace_seq, ts, gvl } => {
+                file.write_trace_end(trace_seq, ts, gvl)
+            }
+            WriterMessage::TagSet { tag_set_seq, tags } => file.write_tag_set(tag_set_seq, tags),
+            WriterMessage::Flush => file.writer.flush(),
+            WriterMessage::EpochEnd { reopen } => file.end_epoch(reopen).map(|rotated| {
+                if rotated {
+                    totals.files += 1;
+                }
+            }),
+        };
+
+        if let Err(e) = result {
+            log::error!("[logger] write sample file failed: {:?}", e);
+        }
+
+        if check_reopen && !reopen_due.load(Ordering::Acquire) {
+            match file.is_reopen_due() {
+                Ok(true) => reopen_due.store(true, Ordering::Release),
+                Ok(false) => {}
+                Err(e) => log::error!("[logger] check sample file failed: {:?}", e),
+            }
+        }
+    }
+
+    if let Err(e) = file.writer.flush() {
+        log::error!("[logger] flush sample file failed: {:?}", e);
+    }
+
+    totals
+}
+
+// A sample is thread_id, ts, trace_seq, tag_set_seq, thread_state, cpu_nanos, wall_nanos, iseq_addr..., SEPARATOR, SEPARATOR,
+// or the same header and (iseq_addr, pc)..., SEPARATOR, SEPARATOR with pcs.
+fn write_samples(
+    writer: &mut dyn RecordWriter,
+    words: &[u64],
+    with_pcs: bool,
+    totals: &mut WriterTotals,
+) -> std::io::Result<()> {
+    let mut start = 0;
+    let mut i = SAMPLE_HEADER_LEN;
+
+    while i + 1 < words.len() {
+        if words[i] == SEPARATOR && words[i + 1] == SEPARATOR {
+            let header = SampleHeader {
+                thread_id: words[start],
+                ts: words[start + 1],
+                trace_seq: words[start + 2],
+                tag_set_seq: words[start + 3],
+                thread_state: words[start + 4] as u8,
+                cpu_nanos: words[start + 5],
+                wall_nanos: words[start + 6],
+            };
+            let frames = &words[start + SAMPLE_HEADER_LEN..i];
+            if with_pcs {
+                writer.write_sample_with_pcs(&header, frames)?;
+            } else {
+                writer.write_sample(&header, frames)?;
+            }
+            totals.samples += 1;
+
+            start = i + 2;
+            i = start + SAMPLE_HEADER_LEN;
+        } else {
+            i += 1;
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+pub(crate) fn expand_path(path: &str) -> String {
+    path.replace("%p", &std::process::id().to_string())
+}
+
+fn count_samples(words: &[u64]) -> u64 {
+    let mut count = 0;
+    let mut i = 0;
+
+    while i + 1 < words.len() {
+        if words[i] == SEPARATOR && words[i + 1] == SEPARATOR {
+            count += 1;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    count
+}
+
+// sdb-<pid>-<start time>.bin in continuous mode,
+// or the start time is inserted before the extension of the given path.
+fn rotated_sample_file_path(path: Option<&str>) -> String {
+    let time = Utc::now().format("%Y%m%d%H%M%S%3f");
+    let path = match path {
+        Some(path) => expand_path(path),
+        None => return format!("sdb-{}-{}.bin", std::process::id(), time),
+    };
+
+    let stem_end = match path.rfind('.') {
+        Some(dot) if !path[dot..].contains('/') => dot,
+        _ => path.len(),
+    };
+
+    format!("{}-{}{}", &path[..stem_end], time, &path[stem_end..])
+}
+
+// The first scanning of a process writes sdb-<pid>.bin,
+// the following ones after restarting write sdb-<pid>.<n>.bin for keeping the previous files.
+pub(crate) fn sample_file_path() -> String {
+    match SAMPLE_FILES_COUNT.fetch_add(1, Ordering::Relaxed) {
+        0 => format!("sdb-{}.bin", std::process::id()),
+        n => format!("sdb-{}.{}.bin", std::process::id(), n),
+    }
+}
+
+unsafe fn config_str(config: VALUE, key: &str) -> Option<String> {
+    let value = rb_sys::rb_hash_aref(config, rb_sys::rb_id2sym(internal_id(key)));
+    if rb_sys::NIL_P(value) {
+        return None;
+    }
+
+    crate::stack_scanner::RUBY_API.ruby_str_to_rust_str(rb_sys::rb_obj_as_string(value))
+}
+
+// config is a hash of path, format, sink and log_path, see Sdb.init_logger
+pub unsafe extern "C" fn rb_open_logger(_module: VALUE, config: VALUE) -> VALUE {
+    Logger::init(&config_str(config, "log_path").unwrap_or("sdb.log".to_string()));
+
+    let output_config = OutputConfig {
+        path: config_str(config, "path"),
+        format: match config_str(config, "format").as_deref() {
+            Some("text") => OutputFormat::Text,
+            _ => OutputFormat::Binary,
+        },
+        sink: match config_str(config, "sink").as_deref() {
+            Some("unix") => Sink::Unix,
+            Some("stdout") => Sink::Stdout,
+            _ => Sink::File,
+        },
+        rotation: ROTATION.lock().unwrap().clone(),
+    };
+
+    let ruby_version = crate::ruby_version::get_ruby_version_string();
+    let mut stack_scanner = crate::stack_scanner::STACK_SCANNER.lock();
+    // samples are written instead of aggregated
+    stack_scanner.aggregator_mut().close();
+
+    match stack_scanner
+        .logger_mut()
+        .open_output(output_config.clone(), &ruby_version)
+    {
+        Ok(path) => {
+            stack_scanner.log_thread_contexts();
+            log::info!(
+                "[{}][logger] writes samples to {:?} {}",
+                std::process::id(),
+                output_config.sink,
+                path
+            );
+            return Qtrue as VALUE;
+        }
+        Err(e) => {
+            log::error!("[logger] open {:?} failed: {:?}", output_config, e);
+            return Qfalse as VALUE;
+        }
+    }
+}
+
+// max_bytes nil turns continuous mode off, it takes effect when the scanner starts next time
+pub unsafe extern "C" fn rb_set_rotation(
+    _module: VALUE,
+    max_bytes: VALUE,
+    max_age: VALUE,
+    keep: VALUE,
+) -> VALUE {
+    *ROTATION.lock().unwrap() = if rb_sys::NIL_P(max_bytes) {
+        None
+    } else {
+        Some(Rotation {
+            max_bytes: rb_sys::rb_num2ulong(max_bytes) as u64,
+            max_age: Duration::from_secs_f64(rb_sys::rb_num2dbl(max_age)),
+            keep: rb_sys::rb_num2long(keep) as usize,
+        })
+    };
+
+    return Qnil as VALUE;
 }
 
 pub unsafe extern "C" fn rb_log(_module: VALUE, log: VALUE) -> VALUE {
@@ -78,3 +1091,87 @@ pub unsafe extern "C" fn rb_log(_module: VALUE, log: VALUE) -> VALUE {
 
     return Qtrue as VALUE;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CaptureBuffer, SymbolSnapshot};
+
+    fn open_test_output(logger: &mut Logger, name: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("sdb-{}-{}.bin", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        let config = OutputConfig {
+            path: Some(path.clone()),
+            format: OutputFormat::Binary,
+            sink: Sink::File,
+            rotation: None,
+        };
+        logger.open_output(config, "3.3.4").unwrap();
+        path
+    }
+
+    fn push_sample(logger: &mut Logger, thread_id: u64, frames: &[u64]) {
+        for word in [thread_id, 1, 0, 0, 0, 0, 0] {
+            logger.push(word);
+        }
+        for frame in frames {
+            logger.push(*frame);
+        }
+        logger.push_seperator();
+    }
+
+    #[test]
+    fn test_sample_longer_than_buffer_is_dropped() {
+        let mut logger = Logger::with_buffer_size(16);
+        let path = open_test_output(&mut logger, "long-sample");
+
+        let frames: Vec<u64> = (1..=32).collect();
+        push_sample(&mut logger, 100, &frames);
+        assert_eq!(logger.total_dropped, 1);
+        assert_eq!(logger.buffer_index, 0);
+
+        // the next samples are kept, the second one is moved to a new buffer
+        push_sample(&mut logger, 100, &[1, 2]);
+        push_sample(&mut logger, 100, &[3, 4]);
+        assert_eq!(logger.total_dropped, 1);
+        assert_eq!(logger.sample_start, 11);
+
+        logger.close();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_without_free_buffer_drops_samples() {
+        // without a writer no buffer is ever free
+        let mut logger = Logger::with_buffer_size(64);
+        push_sample(&mut logger, 100, &[1, 2]);
+        push_sample(&mut logger, 100, &[3, 4]);
+
+        logger.flush();
+        assert_eq!(logger.total_dropped, 2);
+        assert_eq!(logger.dropped, 2);
+        assert_eq!(logger.buffer_index, 0);
+        assert_eq!(logger.sample_start, 0);
+    }
+
+    #[test]
+    fn test_kept_samples_dropped_are_counted() {
+        // the second sample doesn't fit in the buffer and no buffer is free
+        let mut logger = Logger::with_buffer_size(16);
+        let mut samples = CaptureBuffer::new(4);
+        samples.push_sample(&[100, 1, 1, 0, 0, 0, 0, 1, 2]);
+        samples.push_sample(&[100, 2, 1, 0, 0, 0, 0, 3, 4]);
+
+        samples.write_to(&mut logger, &mut SymbolSnapshot::default());
+        assert_eq!(logger.total_dropped, 1);
+        assert_eq!(logger.kept_dropped, 1);
+
+        // other samples dropped aren't
+        let frames: Vec<u64> = (1..=16).collect();
+        push_sample(&mut logger, 100, &frames);
+        assert_eq!(logger.total_dropped, 2);
+        assert_eq!(logger.kept_dropped, 1);
+    }
+}
@@ -2,9 +2,119 @@ use libc::c_void;
 use rb_sys::VALUE;
 use std::ffi::CStr;
 use std::os::raw::c_char;
+use std::sync::OnceLock;
 
 const RSTRING_HEAP_FLAGS: usize = 1 << 13;
 
+// from vm_core.h, they are the same from Ruby 3.1 to 3.4
+const VM_FRAME_MAGIC_MASK: usize = 0x7fff0001;
+const VM_FRAME_MAGIC_CFUNC: usize = 0x55550001;
+const VM_ENV_DATA_INDEX_ME_CREF: isize = -2;
+const VM_ENV_DATA_INDEX_SPECVAL: isize = -1;
+const VM_ENV_DATA_INDEX_FLAGS: isize = 0;
+const VM_ENV_FLAG_LOCAL: usize = 0x0002;
+// rb_thread_status
+const THREAD_RUNNABLE: u32 = 0;
+const THREAD_KILLED: u32 = 3;
+
+const FL_USHIFT: usize = 12;
+const IMEMO_MASK: usize = 0x0F;
+const IMEMO_SVAR: usize = 2;
+const IMEMO_MENT: usize = 6;
+
+extern "C" {
+    // exported by iseq.c, but it isn't in Ruby's public headers
+    fn rb_iseq_line_no(iseq: *const c_void, pos: usize) -> std::os::raw::c_uint;
+}
+
+// rb_iseq_location_struct.first_lineno is a Fixnum VALUE in Ruby 3.1 and an int since Ruby 3.2
+trait Lineno {
+    fn to_lineno(self) -> u32;
+}
+
+impl Lineno for usize {
+    #[inline]
+    fn to_lineno(self) -> u32 {
+        ((self as i64) >> 1) as u32
+    }
+}
+
+impl Lineno for std::os::raw::c_int {
+    #[inline]
+    fn to_lineno(self) -> u32 {
+        self as u32
+    }
+}
+
+#[inline]
+unsafe fn imemo_type(value: VALUE) -> Option<usize> {
+    // special constants, such as Qfalse and Qnil, are not heap objects
+    if value & 0x07 != 0 || value <= rb_sys::Qnil as VALUE {
+        return None;
+    }
+
+    let flags = (*(value as *const rb_sys::RBasic)).flags as usize;
+    if flags & (rb_sys::RUBY_T_MASK as usize) != rb_sys::RUBY_T_IMEMO as usize {
+        return None;
+    }
+
+    Some((flags >> FL_USHIFT) & IMEMO_MASK)
+}
+
+// ep[VM_ENV_DATA_INDEX_ME_CREF] holds a method entry, a cref or a svar which holds one of them,
+// the same as check_method_entry in vm_insnhelper.c
+#[inline]
+unsafe fn method_entry_of(me_cref: VALUE, can_be_svar: bool) -> u64 {
+    match imemo_type(me_cref) {
+        Some(IMEMO_MENT) => me_cref as u64,
+        Some(IMEMO_SVAR) if can_be_svar => {
+            // vm_svar's second field is cref_or_me
+            method_entry_of(*(me_cref as *const VALUE).add(1), false)
+        }
+        _ => 0,
+    }
+}
+
+#[inline]
+unsafe fn is_module(value: VALUE) -> bool {
+    if value & 0x07 != 0 || value <= rb_sys::Qnil as VALUE {
+        return false;
+    }
+
+    let flags = (*(value as *const rb_sys::RBasic)).flags as usize;
+    let value_type = flags & (rb_sys::RUBY_T_MASK as usize);
+    value_type == rb_sys::RUBY_T_CLASS as usize || value_type == rb_sys::RUBY_T_MODULE as usize
+}
+
+#[inline]
+unsafe fn is_singleton_class(klass: VALUE) -> bool {
+    is_module(klass)
+        && (*(klass as *const rb_sys::RBasic)).flags as usize
+            & rb_sys::ruby_fl_type::RUBY_FL_SINGLETON as usize
+            != 0
+}
+
+// rb_class_attached_object is exported since Ruby 3.2,
+// Ruby 3.1 keeps the attached object in the __attached__ ivar of the singleton class.
+unsafe fn singleton_attached_object(klass: VALUE) -> VALUE {
+    static ATTACHED_OBJECT_FN: OnceLock<usize> = OnceLock::new();
+
+    let func = *ATTACHED_OBJECT_FN.get_or_init(|| unsafe {
+        libc::dlsym(
+            libc::RTLD_DEFAULT,
+            "rb_class_attached_object\0".as_ptr() as *const c_char,
+        ) as usize
+    });
+
+    if func != 0 {
+        let func: unsafe extern "C" fn(VALUE) -> VALUE = std::mem::transmute(func);
+        func(klass)
+    } else {
+        let attached_id = rb_sys::rb_intern("__attached__\0".as_ptr() as *const c_char);
+        rb_sys::rb_attr_get(klass, attached_id)
+    }
+}
+
 macro_rules! impl_ruby_str_to_rust_str {
     ($rstring_type:path) => {
         #[inline]
@@ -79,6 +189,40 @@ macro_rules! impl_iseq_functions {
             body.location.first_lineno as VALUE
         }
 
+        #[inline]
+        unsafe fn get_first_lineno_num(&self, iseq_addr: u64) -> u32 {
+            use $iseq_struct as rb_iseq_struct;
+            let iseq = &*(iseq_addr as *const rb_iseq_struct);
+            let body = &*iseq.body;
+            body.location.first_lineno.to_lineno()
+        }
+
+        // Translates a control frame's pc to the line through the iseq's insns_info table.
+        // GVL must be hold, as the iseq must not be freed or moved.
+        #[inline]
+        unsafe fn get_lineno(&self, iseq_addr: u64, pc: u64) -> u32 {
+            use $iseq_struct as rb_iseq_struct;
+            let iseq = &*(iseq_addr as *const rb_iseq_struct);
+            let body = &*iseq.body;
+            let iseq_encoded = body.iseq_encoded as u64;
+
+            if pc < iseq_encoded {
+                return 0;
+            }
+
+            let mut pos = ((pc - iseq_encoded) as usize) / std::mem::size_of::<VALUE>();
+            if pos > body.iseq_size as usize {
+                return 0;
+            }
+
+            // pc points to the next instruction, the same as calc_pos in vm_backtrace.c
+            if pos > 0 {
+                pos -= 1;
+            }
+
+            rb_iseq_line_no(iseq_addr as *const c_void, pos) as u32
+        }
+
         #[inline]
         unsafe fn get_label(&self, iseq_addr: u64) -> VALUE {
             use $iseq_struct as rb_iseq_struct;
@@ -127,14 +271,64 @@ macro_rules! impl_iseq_functions {
 
             use $iseq_struct as rb_iseq_struct;
             let iseq = &*(iseq_ptr as *const rb_iseq_struct);
-            const FL_USHIFT: usize = 12;
-            const IMEMO_MASK: usize = 0x0F;
             const IMEMO_ISEQ: usize = 7;
             (iseq.flags >> FL_USHIFT) & IMEMO_MASK == IMEMO_ISEQ
         }
     };
 }
 
+macro_rules! impl_method_entry_functions {
+    ($method_entry_struct:path) => {
+        #[inline]
+        unsafe fn is_method_entry_imemo(&self, me_ptr: *const c_void) -> bool {
+            if me_ptr.is_null() {
+                return false;
+            }
+
+            imemo_type(me_ptr as VALUE) == Some(IMEMO_MENT)
+        }
+
+        // Returns the owner's name, the method's name and whether it is a singleton method,
+        // the owner of a singleton method is named by the object it is attached to.
+        // GVL must be hold.
+        #[inline]
+        unsafe fn get_method_entry_info(
+            &self,
+            me_addr: u64,
+        ) -> (Option<String>, Option<String>, bool) {
+            use $method_entry_struct as rb_method_entry_struct;
+            let me = &*(me_addr as *const rb_method_entry_struct);
+
+            let mut owner = me.owner as VALUE;
+            let singleton = is_singleton_class(owner);
+            if singleton {
+                owner = singleton_attached_object(owner);
+            }
+
+            // rb_mod_name returns the cached class path without allocating, nil for anonymous classes
+            let owner_str = if !is_module(owner) {
+                None
+            } else {
+                let name = rb_sys::rb_mod_name(owner);
+                if rb_sys::NIL_P(name) {
+                    None
+                } else {
+                    self.ruby_str_to_rust_str(name)
+                }
+            };
+
+            let method_id = if me.def.is_null() {
+                me.called_id
+            } else {
+                (*me.def).original_id
+            };
+            let method_str = self.ruby_str_to_rust_str(rb_sys::rb_id2str(method_id as rb_sys::ID));
+
+            (owner_str, method_str, singleton)
+        }
+    };
+}
+
 macro_rules! impl_thread_functions {
     ($thread_struct:path) => {
         #[inline]
@@ -146,6 +340,76 @@ macro_rules! impl_thread_functions {
             let thread_struct = &*thread_struct_ptr;
             thread_struct.ec as *mut c_void
         }
+
+        // The status is set to THREAD_KILLED before the thread clears its vm stack
+        #[inline]
+        unsafe fn is_thread_killed(&self, thread_val: VALUE) -> bool {
+            use rb_sys::RTypedData;
+            use $thread_struct as rb_thread_t;
+            let thread_ptr: *mut RTypedData = thread_val as *mut RTypedData;
+            let thread_struct = &*((*thread_ptr).data as *mut rb_thread_t);
+            thread_struct.status() as u32 == THREAD_KILLED
+        }
+
+        // A thread is THREAD_STOPPED while it sleeps, waits or runs in a blocking region without the GVL,
+        // so a runnable thread runs with the GVL or waits for it
+        #[inline]
+        unsafe fn is_thread_runnable(&self, thread_val: VALUE) -> bool {
+            use rb_sys::RTypedData;
+            use $thread_struct as rb_thread_t;
+            let thread_ptr: *mut RTypedData = thread_val as *mut RTypedData;
+            let thread_struct = &*((*thread_ptr).data as *mut rb_thread_t);
+            thread_struct.status() as u32 == THREAD_RUNNABLE
+        }
+    };
+}
+
+// An exiting thread clears its vm stack with rb_ec_clear_vm_stack, which sets vm_stack and cfp to NULL.
+// The scanner reads them without the GVL, so a half cleared one is also rejected.
+#[inline]
+fn has_frames<V, F>(vm_stack: *mut V, vm_stack_size: usize, cfp: *mut F) -> bool {
+    if vm_stack.is_null() || cfp.is_null() {
+        return false;
+    }
+
+    let stack_start = vm_stack as usize;
+    let stack_end = stack_start + vm_stack_size * std::mem::size_of::<V>();
+    (stack_start..=stack_end).contains(&(cfp as usize))
+}
+
+// The GVL is per ractor, a thread finds it by its ractor.
+// Ruby 3.1 has rb_global_vm_lock_t in ractor->threads.gvl, Ruby 3.2 renamed it to the thread scheduler
+// in ractor->threads.sched, and Ruby 3.3 renamed its lock to lock_ for M:N threads.
+macro_rules! impl_gvl_functions {
+    (@fields $thread_struct:path, $sched:ident, $lock:ident, $owner:ident) => {
+        #[inline]
+        unsafe fn get_gvl_lock_addr(&self, thread_val: VALUE) -> u64 {
+            use rb_sys::RTypedData;
+            use $thread_struct as rb_thread_t;
+            let thread_ptr: *mut RTypedData = thread_val as *mut RTypedData;
+            let thread_struct = &*((*thread_ptr).data as *mut rb_thread_t);
+            let sched = &(*thread_struct.ractor).threads.$sched;
+            &sched.$lock as *const _ as u64
+        }
+
+        // the rb_thread_t holding the GVL of the thread's ractor, 0 when no thread holds it
+        #[inline]
+        unsafe fn get_gvl_owner(&self, thread_val: VALUE) -> u64 {
+            use rb_sys::RTypedData;
+            use $thread_struct as rb_thread_t;
+            let thread_ptr: *mut RTypedData = thread_val as *mut RTypedData;
+            let thread_struct = &*((*thread_ptr).data as *mut rb_thread_t);
+            (*thread_struct.ractor).threads.$sched.$owner as u64
+        }
+    };
+    ($thread_struct:path, gvl) => {
+        impl_gvl_functions!(@fields $thread_struct, gvl, lock, owner);
+    };
+    ($thread_struct:path, sched) => {
+        impl_gvl_functions!(@fields $thread_struct, sched, lock, running);
+    };
+    ($thread_struct:path, sched_lock_) => {
+        impl_gvl_functions!(@fields $thread_struct, sched, lock_, running);
     };
 }
 
@@ -157,19 +421,96 @@ macro_rules! impl_control_frame_functions {
             std::mem::size_of::<rb_control_frame_struct>()
         }
 
+        // A CFUNC frame doesn't have an iseq, vm_call_cfunc_with_frame pushes it with the
+        // method entry in ep[VM_ENV_DATA_INDEX_ME_CREF], so the method entry's address is used instead.
+        #[inline]
+        unsafe fn get_frame_addr(&self, frame: *const c_void) -> u64 {
+            use $control_frame_struct as rb_control_frame_struct;
+            let frame = &*(frame as *const rb_control_frame_struct);
+            let iseq_addr = frame.iseq as u64;
+
+            if iseq_addr != 0 || frame.ep.is_null() {
+                return iseq_addr;
+            }
+
+            let flags = *frame.ep.offset(VM_ENV_DATA_INDEX_FLAGS) as usize;
+            if flags & VM_FRAME_MAGIC_MASK == VM_FRAME_MAGIC_CFUNC {
+                *frame.ep.offset(VM_ENV_DATA_INDEX_ME_CREF) as u64
+            } else {
+                0
+            }
+        }
+
+        // The method entry of the method a frame runs in, 0 when it isn't in a method,
+        // such as the top level or a class body. A block's frame uses its method's method entry,
+        // the same as rb_vm_frame_method_entry.
+        #[inline]
+        unsafe fn get_frame_method_entry(&self, frame: *const c_void) -> u64 {
+            use $control_frame_struct as rb_control_frame_struct;
+            let frame = &*(frame as *const rb_control_frame_struct);
+
+            if frame.iseq.is_null() || frame.ep.is_null() {
+                return 0;
+            }
+
+            let mut ep = frame.ep;
+            while *ep.offset(VM_ENV_DATA_INDEX_FLAGS) as usize & VM_ENV_FLAG_LOCAL == 0 {
+                let me = method_entry_of(*ep.offset(VM_ENV_DATA_INDEX_ME_CREF) as VALUE, false);
+                if me != 0 {
+                    return me;
+                }
+
+                // the previous ep is guarded by setting the lowest bits
+                ep = (*ep.offset(VM_ENV_DATA_INDEX_SPECVAL) & !0x03) as *const _;
+                if ep.is_null() {
+                    return 0;
+                }
+            }
+
+            method_entry_of(*ep.offset(VM_ENV_DATA_INDEX_ME_CREF) as VALUE, true)
+        }
+
         #[inline]
-        unsafe fn iterate_frame_iseqs(&self, ec_val: VALUE, iseq_handler: &mut dyn FnMut(u64)) {
+        unsafe fn iterate_frame_iseqs(
+            &self,
+            ec_val: VALUE,
+            iseq_handler: &mut dyn FnMut(u64, *const c_void),
+        ) {
             use $execution_context_struct as rb_execution_context_struct;
             let ec = *(ec_val as *mut rb_execution_context_struct);
+            if !has_frames(ec.vm_stack, ec.vm_stack_size, ec.cfp) {
+                return;
+            }
             let stack_base = ec.vm_stack.add(ec.vm_stack_size);
             let diff = (stack_base as usize) - (ec.cfp as usize);
             let len = diff / self.get_control_frame_struct_size();
             let frames = std::slice::from_raw_parts(ec.cfp, len);
 
             for frame in frames {
-                let iseq = &*frame.iseq;
-                let iseq_addr = iseq as *const _ as u64;
-                iseq_handler(iseq_addr);
+                let frame_ptr = frame as *const _ as *const c_void;
+                iseq_handler(self.get_frame_addr(frame_ptr), frame_ptr);
+            }
+        }
+
+        #[inline]
+        unsafe fn iterate_frames(
+            &self,
+            ec_val: VALUE,
+            frame_handler: &mut dyn FnMut(u64, u64, *const c_void),
+        ) {
+            use $execution_context_struct as rb_execution_context_struct;
+            let ec = *(ec_val as *mut rb_execution_context_struct);
+            if !has_frames(ec.vm_stack, ec.vm_stack_size, ec.cfp) {
+                return;
+            }
+            let stack_base = ec.vm_stack.add(ec.vm_stack_size);
+            let diff = (stack_base as usize) - (ec.cfp as usize);
+            let len = diff / self.get_control_frame_struct_size();
+            let frames = std::slice::from_raw_parts(ec.cfp, len);
+
+            for frame in frames {
+                let frame_ptr = frame as *const _ as *const c_void;
+                frame_handler(self.get_frame_addr(frame_ptr), frame.pc as u64, frame_ptr);
             }
         }
     };
@@ -220,128 +561,149 @@ pub enum RubyVersion {
 pub trait RubyApiCompat: Send + Sync {
     unsafe fn get_iseq_info(&self, iseq_addr: u64) -> (Option<String>, Option<String>);
     unsafe fn get_first_lineno(&self, iseq_addr: u64) -> VALUE;
+    unsafe fn get_first_lineno_num(&self, iseq_addr: u64) -> u32;
+    unsafe fn get_lineno(&self, iseq_addr: u64, pc: u64) -> u32;
     unsafe fn get_label(&self, iseq_addr: u64) -> VALUE;
     unsafe fn get_base_label(&self, iseq_addr: u64) -> VALUE;
     unsafe fn ruby_str_to_rust_str(&self, ruby_str: VALUE) -> Option<String>;
     unsafe fn extract_path_string(&self, path: VALUE) -> Option<String>;
     unsafe fn is_iseq_imemo(&self, iseq_ptr: *const c_void) -> bool;
+    unsafe fn is_method_entry_imemo(&self, me_ptr: *const c_void) -> bool;
+    unsafe fn get_method_entry_info(&self, me_addr: u64) -> (Option<String>, Option<String>, bool);
     unsafe fn get_ec_from_thread(&self, thread_val: VALUE) -> *mut c_void;
+    unsafe fn is_thread_killed(&self, thread_val: VALUE) -> bool;
+    unsafe fn is_thread_runnable(&self, thread_val: VALUE) -> bool;
+    unsafe fn get_gvl_lock_addr(&self, thread_val: VALUE) -> u64;
+    unsafe fn get_gvl_owner(&self, thread_val: VALUE) -> u64;
     fn get_control_frame_struct_size(&self) -> usize;
-    unsafe fn iterate_frame_iseqs(&self, ec_val: VALUE, frame_handler: &mut dyn FnMut(u64));
+    unsafe fn get_frame_addr(&self, frame: *const c_void) -> u64;
+    unsafe fn get_frame_method_entry(&self, frame: *const c_void) -> u64;
+    unsafe fn iterate_frame_iseqs(
+        &self,
+        ec_val: VALUE,
+        frame_handler: &mut dyn FnMut(u64, *const c_void),
+    );
+    unsafe fn iterate_frames(
+        &self,
+        ec_val: VALUE,
+        frame_handler: &mut dyn FnMut(u64, u64, *const c_void),
+    );
 }
 
 // Macro to reduce duplication for Ruby version implementations
 macro_rules! impl_ruby_version {
     // Ruby 3.1.x
     (Ruby310) => {
-        impl_ruby_version_with_module!(Ruby310, ruby_3_1_0);
+        impl_ruby_version_with_module!(Ruby310, ruby_3_1_0, gvl);
     };
     (Ruby311) => {
-        impl_ruby_version_with_module!(Ruby311, ruby_3_1_1);
+        impl_ruby_version_with_module!(Ruby311, ruby_3_1_1, gvl);
     };
     (Ruby312) => {
-        impl_ruby_version_with_module!(Ruby312, ruby_3_1_2);
+        impl_ruby_version_with_module!(Ruby312, ruby_3_1_2, gvl);
     };
     (Ruby313) => {
-        impl_ruby_version_with_module!(Ruby313, ruby_3_1_3);
+        impl_ruby_version_with_module!(Ruby313, ruby_3_1_3, gvl);
     };
     (Ruby314) => {
-        impl_ruby_version_with_module!(Ruby314, ruby_3_1_4);
+        impl_ruby_version_with_module!(Ruby314, ruby_3_1_4, gvl);
     };
     (Ruby315) => {
-        impl_ruby_version_with_module!(Ruby315, ruby_3_1_5);
+        impl_ruby_version_with_module!(Ruby315, ruby_3_1_5, gvl);
     };
     (Ruby316) => {
-        impl_ruby_version_with_module!(Ruby316, ruby_3_1_6);
+        impl_ruby_version_with_module!(Ruby316, ruby_3_1_6, gvl);
     };
     (Ruby317) => {
-        impl_ruby_version_with_module!(Ruby317, ruby_3_1_7);
+        impl_ruby_version_with_module!(Ruby317, ruby_3_1_7, gvl);
     };
 
     // Ruby 3.2.x
     (Ruby320) => {
-        impl_ruby_version_with_module!(Ruby320, ruby_3_2_0);
+        impl_ruby_version_with_module!(Ruby320, ruby_3_2_0, sched);
     };
     (Ruby321) => {
-        impl_ruby_version_with_module!(Ruby321, ruby_3_2_1);
+        impl_ruby_version_with_module!(Ruby321, ruby_3_2_1, sched);
     };
     (Ruby322) => {
-        impl_ruby_version_with_module!(Ruby322, ruby_3_2_2);
+        impl_ruby_version_with_module!(Ruby322, ruby_3_2_2, sched);
     };
     (Ruby323) => {
-        impl_ruby_version_with_module!(Ruby323, ruby_3_2_3);
+        impl_ruby_version_with_module!(Ruby323, ruby_3_2_3, sched);
     };
     (Ruby324) => {
-        impl_ruby_version_with_module!(Ruby324, ruby_3_2_4);
+        impl_ruby_version_with_module!(Ruby324, ruby_3_2_4, sched);
     };
     (Ruby325) => {
-        impl_ruby_version_with_module!(Ruby325, ruby_3_2_5);
+        impl_ruby_version_with_module!(Ruby325, ruby_3_2_5, sched);
     };
     (Ruby326) => {
-        impl_ruby_version_with_module!(Ruby326, ruby_3_2_6);
+        impl_ruby_version_with_module!(Ruby326, ruby_3_2_6, sched);
     };
     (Ruby327) => {
-        impl_ruby_version_with_module!(Ruby327, ruby_3_2_7);
+        impl_ruby_version_with_module!(Ruby327, ruby_3_2_7, sched);
     };
     (Ruby328) => {
-        impl_ruby_version_with_module!(Ruby328, ruby_3_2_8);
+        impl_ruby_version_with_module!(Ruby328, ruby_3_2_8, sched);
     };
 
     // Ruby 3.3.x
     (Ruby330) => {
-        impl_ruby_version_with_module!(Ruby330, ruby_3_3_0);
+        impl_ruby_version_with_module!(Ruby330, ruby_3_3_0, sched_lock_);
     };
     (Ruby331) => {
-        impl_ruby_version_with_module!(Ruby331, ruby_3_3_1);
+        impl_ruby_version_with_module!(Ruby331, ruby_3_3_1, sched_lock_);
     };
     (Ruby332) => {
-        impl_ruby_version_with_module!(Ruby332, ruby_3_3_2);
+        impl_ruby_version_with_module!(Ruby332, ruby_3_3_2, sched_lock_);
     };
     (Ruby333) => {
-        impl_ruby_version_with_module!(Ruby333, ruby_3_3_3);
+        impl_ruby_version_with_module!(Ruby333, ruby_3_3_3, sched_lock_);
     };
     (Ruby334) => {
-        impl_ruby_version_with_module!(Ruby334, ruby_3_3_4);
+        impl_ruby_version_with_module!(Ruby334, ruby_3_3_4, sched_lock_);
     };
     (Ruby335) => {
-        impl_ruby_version_with_module!(Ruby335, ruby_3_3_5);
+        impl_ruby_version_with_module!(Ruby335, ruby_3_3_5, sched_lock_);
     };
     (Ruby336) => {
-        impl_ruby_version_with_module!(Ruby336, ruby_3_3_6);
+        impl_ruby_version_with_module!(Ruby336, ruby_3_3_6, sched_lock_);
     };
     (Ruby337) => {
-        impl_ruby_version_with_module!(Ruby337, ruby_3_3_7);
+        impl_ruby_version_with_module!(Ruby337, ruby_3_3_7, sched_lock_);
     };
     (Ruby338) => {
-        impl_ruby_version_with_module!(Ruby338, ruby_3_3_8);
+        impl_ruby_version_with_module!(Ruby338, ruby_3_3_8, sched_lock_);
     };
 
     // Ruby 3.4.x
     (Ruby340) => {
-        impl_ruby_version_with_module!(Ruby340, ruby_3_4_0);
+        impl_ruby_version_with_module!(Ruby340, ruby_3_4_0, sched_lock_);
     };
     (Ruby341) => {
-        impl_ruby_version_with_module!(Ruby341, ruby_3_4_1);
+        impl_ruby_version_with_module!(Ruby341, ruby_3_4_1, sched_lock_);
     };
     (Ruby342) => {
-        impl_ruby_version_with_module!(Ruby342, ruby_3_4_2);
+        impl_ruby_version_with_module!(Ruby342, ruby_3_4_2, sched_lock_);
     };
     (Ruby343) => {
-        impl_ruby_version_with_module!(Ruby343, ruby_3_4_3);
+        impl_ruby_version_with_module!(Ruby343, ruby_3_4_3, sched_lock_);
     };
     (Ruby344) => {
-        impl_ruby_version_with_module!(Ruby344, ruby_3_4_4);
+        impl_ruby_version_with_module!(Ruby344, ruby_3_4_4, sched_lock_);
     };
 }
 
 // Helper macro that does the actual implementation
 macro_rules! impl_ruby_version_with_module {
-    ($struct_name:ident, $module:ident) => {
+    ($struct_name:ident, $module:ident, $gvl_layout:ident) => {
         pub struct $struct_name;
 
         impl RubyApiCompat for $struct_name {
             impl_iseq_functions!(rbspy_ruby_structs::$module::rb_iseq_struct);
+            impl_method_entry_functions!(rbspy_ruby_structs::$module::rb_method_entry_struct);
             impl_thread_functions!(rbspy_ruby_structs::$module::rb_thread_t);
+            impl_gvl_functions!(rbspy_ruby_structs::$module::rb_thread_t, $gvl_layout);
             impl_control_frame_functions!(
                 rbspy_ruby_structs::$module::rb_control_frame_struct,
                 rbspy_ruby_structs::$module::rb_execution_context_struct
@@ -449,6 +811,14 @@ impl RubyAPI {
         self.inner.get_first_lineno(iseq_addr)
     }
 
+    pub unsafe fn get_first_lineno_num(&self, iseq_addr: u64) -> u32 {
+        self.inner.get_first_lineno_num(iseq_addr)
+    }
+
+    pub unsafe fn get_lineno(&self, iseq_addr: u64, pc: u64) -> u32 {
+        self.inner.get_lineno(iseq_addr, pc)
+    }
+
     pub unsafe fn ruby_str_to_rust_str(&self, ruby_str: VALUE) -> Option<String> {
         self.inner.ruby_str_to_rust_str(ruby_str)
     }
@@ -465,17 +835,68 @@ impl RubyAPI {
         self.inner.is_iseq_imemo(iseq_ptr)
     }
 
+    pub unsafe fn is_method_entry_imemo(&self, me_ptr: *const c_void) -> bool {
+        self.inner.is_method_entry_imemo(me_ptr)
+    }
+
+    pub unsafe fn get_method_entry_info(
+        &self,
+        me_addr: u64,
+    ) -> (Option<String>, Option<String>, bool) {
+        self.inner.get_method_entry_info(me_addr)
+    }
+
+    pub unsafe fn get_frame_method_entry(&self, frame: *const c_void) -> u64 {
+        self.inner.get_frame_method_entry(frame)
+    }
+
     pub unsafe fn get_ec_from_thread(&self, thread_val: VALUE) -> *mut c_void {
         self.inner.get_ec_from_thread(thread_val)
     }
 
     #[inline]
-    pub unsafe fn iterate_frame_iseqs(&self, ec_val: VALUE, frame_handler: &mut dyn FnMut(u64)) {
+    pub unsafe fn is_thread_killed(&self, thread_val: VALUE) -> bool {
+        self.inner.is_thread_killed(thread_val)
+    }
+
+    #[inline]
+    pub unsafe fn is_thread_runnable(&self, thread_val: VALUE) -> bool {
+        self.inner.is_thread_runnable(thread_val)
+    }
+
+    // the native mutex of the GVL, which sdb-shim logs lock events of
+    #[inline]
+    pub unsafe fn get_gvl_lock_addr(&self, thread_val: VALUE) -> u64 {
+        self.inner.get_gvl_lock_addr(thread_val)
+    }
+
+    #[inline]
+    pub unsafe fn get_gvl_owner(&self, thread_val: VALUE) -> u64 {
+        self.inner.get_gvl_owner(thread_val)
+    }
+
+    // frame_handler receives the iseq address and the control frame
+    #[inline]
+    pub unsafe fn iterate_frame_iseqs(
+        &self,
+        ec_val: VALUE,
+        frame_handler: &mut dyn FnMut(u64, *const c_void),
+    ) {
         self.inner.iterate_frame_iseqs(ec_val, frame_handler)
     }
+
+    // frame_handler receives the iseq address, the pc and the control frame
+    #[inline]
+    pub unsafe fn iterate_frames(
+        &self,
+        ec_val: VALUE,
+        frame_handler: &mut dyn FnMut(u64, u64, *const c_void),
+    ) {
+        self.inner.iterate_frames(ec_val, frame_handler)
+    }
 }
 
-unsafe fn get_ruby_version_string() -> String {
+pub(crate) unsafe fn get_ruby_version_string() -> String {
     let version_sym = rb_sys::rb_intern("RUBY_VERSION\0".as_ptr() as *const c_char);
     let version_val = rb_sys::rb_const_get(rb_sys::rb_cObject, version_sym);
 
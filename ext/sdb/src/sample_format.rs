@@ -0,0 +1,381 @@
+// Binary sample file layout, all integers are little endian.
+//
+// header:
+//   magic "SDBPROF\0" | format_version u16 | clock_source u8 | pid u32 | ruby_version str
+//
+// records, repeated until EOF:
+//   kind u8 | payload_len u32 | payload
+//
+//   sample payload: thread_id u64 | ts u64 | frames_count u32 | iseq_addr u64 * frames_count
+//                   | trace_seq u64 | tag_set_seq u64 | thread_state u8 | cpu_nanos u64 | wall_nanos u64
+//   sample with pcs payload: thread_id u64 | ts u64 | frames_count u32 | (iseq_addr u64 | pc u64) * frames_count
+//                            | trace_seq u64 | tag_set_seq u64 | thread_state u8 | cpu_nanos u64 | wall_nanos u64
+//   symbol payload: iseq_addr u64 | label str | path str | first_lineno u32
+//   dropped payload: samples_count u64, samples dropped since the previous record
+//   line payload: iseq_addr u64 | pc u64 | lineno u32
+//   trace payload: trace_seq u64 | thread_id u64 | begin_ts u64 | trace_id str
+//   trace end payload: trace_seq u64 | end_ts u64 | gvl_wait_nanos u64 | gvl_run_nanos u64
+//   tag set payload: tag_set_seq u64 | tags_count u32 | (key str | value str) * tags_count
+//
+// trace_seq of a sample is the request the thread was serving, 0 when there isn't one.
+// A trace record comes before the samples of its request, the trace end record may come
+// before the last ones. GVL times of a request are 0 without thread event hooks, before Ruby 3.2.
+// Samples of a captured request taken before an earlier epoch end have snapshot ids with the top bit
+// set instead of iseq addresses, their symbol and line records come with the epoch they are written in.
+// tag_set_seq of a sample is the tags of the thread set by Sdb.tag, 0 when it has none.
+// A tag set record comes before the samples with it, and it never changes.
+// thread_state of a sample is 1 running on CPU, 2 waiting for the GVL or 3 blocked, see ThreadState.
+// cpu_nanos of a sample is the CPU time the thread used since its previous sample, 0 when its CPU clock
+// isn't available. wall_nanos is the wall time since its previous sample, the sampling interval for
+// its first one. Both are measured whether or not the previous sample was written.
+//
+// str is encoded as len u32 | utf8 bytes.
+//
+// Readers should skip records whose kind they don't know by payload_len and ignore trailing bytes
+// of a payload, so new record kinds and fields can be added without bumping the format version.
+//
+// The text format writes the same lines as the legacy fast_log sdb.log, for tools reading it:
+//   [pid][stack_frames][thread_id, ts, iseq_addr..., u64::MAX, u64::MAX]
+//   [pid][symbol]iseq_addr, label, path
+// pcs, lines, traces and tags are not written in the text format.
+use std::io::{self, Write};
+
+pub const MAGIC: &[u8; 8] = b"SDBPROF\0";
+pub const FORMAT_VERSION: u16 = 1;
+
+pub const RECORD_SAMPLE: u8 = 1;
+pub const RECORD_SYMBOL: u8 = 2;
+pub const RECORD_DROPPED: u8 = 3;
+pub const RECORD_SAMPLE_WITH_PCS: u8 = 4;
+pub const RECORD_LINE: u8 = 5;
+pub const RECORD_TRACE: u8 = 6;
+pub const RECORD_TRACE_END: u8 = 7;
+pub const RECORD_TAG_SET: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ClockSource {
+    // microseconds since unix epoch, from chrono::Utc::now()
+    RealtimeMicros = 1,
+}
+
+// The fields of a sample besides its frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleHeader {
+    pub thread_id: u64,
+    pub ts: u64,
+    pub trace_seq: u64,
+    pub tag_set_seq: u64,
+    pub thread_state: u8,
+    pub cpu_nanos: u64,
+    pub wall_nanos: u64,
+}
+
+pub struct SampleWriter<W: Write> {
+    inner: W,
+    // reused between records for avoiding allocation on every sample
+    payload: Vec<u8>,
+}
+
+pub trait RecordWriter {
+    fn write_header(
+        &mut self,
+        clock_source: ClockSource,
+        pid: u32,
+        ruby_version: &str,
+    ) -> io::Result<()>;
+    fn write_sample(&mut self, header: &SampleHeader, frames: &[u64]) -> io::Result<()>;
+    // words are iseq_addr and pc pairs
+    fn write_sample_with_pcs(&mut self, header: &SampleHeader, words: &[u64]) -> io::Result<()>;
+    fn write_symbol(
+        &mut self,
+        iseq_addr: u64,
+        label: &str,
+        path: &str,
+        first_lineno: u32,
+    ) -> io::Result<()>;
+    fn write_line(&mut self, iseq_addr: u64, pc: u64, lineno: u32) -> io::Result<()>;
+    fn write_dropped(&mut self, samples_count: u64) -> io::Result<()>;
+    fn write_trace(
+        &mut self,
+        trace_seq: u64,
+        thread_id: u64,
+        begin_ts: u64,
+        trace_id: &str,
+    ) -> io::Result<()>;
+    fn write_trace_end(
+        &mut self,
+        trace_seq: u64,
+        end_ts: u64,
+        gvl_wait_nanos: u64,
+        gvl_run_nanos: u64,
+    ) -> io::Result<()>;
+    fn write_tag_set(&mut self, tag_set_seq: u64, tags: &[(String, String)]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl<W: Write> SampleWriter<W> {
+    pub fn new(inner: W) -> Self {
+        SampleWriter {
+            inner,
+            payload: Vec::with_capacity(4096),
+        }
+    }
+
+    #[inline]
+    fn write_sample_header(&mut self, header: &SampleHeader, frames_count: usize) {
+        self.payload.clear();
+        self.payload
+            .extend_from_slice(&header.thread_id.to_le_bytes());
+        self.payload.extend_from_slice(&header.ts.to_le_bytes());
+        self.payload
+            .extend_from_slice(&(frames_count as u32).to_le_bytes());
+    }
+
+    // the fields after the frames, added after the first version of the format
+    #[inline]
+    fn write_sample_trailer(&mut self, header: &SampleHeader) {
+        self.payload
+            .extend_from_slice(&header.trace_seq.to_le_bytes());
+        self.payload
+            .extend_from_slice(&header.tag_set_seq.to_le_bytes());
+        self.payload.push(header.thread_state);
+        self.payload
+            .extend_from_slice(&header.cpu_nanos.to_le_bytes());
+        self.payload
+            .extend_from_slice(&header.wall_nanos.to_le_bytes());
+    }
+
+    #[inline]
+    fn write_record(&mut self, kind: u8) -> io::Result<()> {
+        self.inner.write_all(&[kind])?;
+        self.inner
+            .write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&self.payload)
+    }
+}
+
+impl<W: Write> RecordWriter for SampleWriter<W> {
+    fn write_header(
+        &mut self,
+        clock_source: ClockSource,
+        pid: u32,
+        ruby_version: &str,
+    ) -> io::Result<()> {
+        self.inner.write_all(MAGIC)?;
+        self.inner.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        self.inner.write_all(&[clock_source as u8])?;
+        self.inner.write_all(&pid.to_le_bytes())?;
+        write_str(&mut self.inner, ruby_version)
+    }
+
+    #[inline]
+    fn write_sample(&mut self, header: &SampleHeader, frames: &[u64]) -> io::Result<()> {
+        self.write_sample_header(header, frames.len());
+        for frame in frames {
+            self.payload.extend_from_slice(&frame.to_le_bytes());
+        }
+        self.write_sample_trailer(header);
+
+        self.write_record(RECORD_SAMPLE)
+    }
+
+    #[inline]
+    fn write_sample_with_pcs(&mut self, header: &SampleHeader, words: &[u64]) -> io::Result<()> {
+        self.write_sample_header(header, words.len() / 2);
+        for word in words {
+            self.payload.extend_from_slice(&word.to_le_bytes());
+        }
+        self.write_sample_trailer(header);
+
+        self.write_record(RECORD_SAMPLE_WITH_PCS)
+    }
+
+    fn write_symbol(
+        &mut self,
+        iseq_addr: u64,
+        label: &str,
+        path: &str,
+        first_lineno: u32,
+    ) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&iseq_addr.to_le_bytes());
+        write_str(&mut self.payload, label)?;
+        write_str(&mut self.payload, path)?;
+        self.payload.extend_from_slice(&first_lineno.to_le_bytes());
+
+        self.write_record(RECORD_SYMBOL)
+    }
+
+    fn write_line(&mut self, iseq_addr: u64, pc: u64, lineno: u32) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&iseq_addr.to_le_bytes());
+        self.payload.extend_from_slice(&pc.to_le_bytes());
+        self.payload.extend_from_slice(&lineno.to_le_bytes());
+
+        self.write_record(RECORD_LINE)
+    }
+
+    fn write_dropped(&mut self, samples_count: u64) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&samples_count.to_le_bytes());
+
+        self.write_record(RECORD_DROPPED)
+    }
+
+    fn write_trace(
+        &mut self,
+        trace_seq: u64,
+        thread_id: u64,
+        begin_ts: u64,
+        trace_id: &str,
+    ) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&trace_seq.to_le_bytes());
+        self.payload.extend_from_slice(&thread_id.to_le_bytes());
+        self.payload.extend_from_slice(&begin_ts.to_le_bytes());
+        write_str(&mut self.payload, trace_id)?;
+
+        self.write_record(RECORD_TRACE)
+    }
+
+    fn write_trace_end(
+        &mut self,
+        trace_seq: u64,
+        end_ts: u64,
+        gvl_wait_nanos: u64,
+        gvl_run_nanos: u64,
+    ) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&trace_seq.to_le_bytes());
+        self.payload.extend_from_slice(&end_ts.to_le_bytes());
+        self.payload
+            .extend_from_slice(&gvl_wait_nanos.to_le_bytes());
+        self.payload.extend_from_slice(&gvl_run_nanos.to_le_bytes());
+
+        self.write_record(RECORD_TRACE_END)
+    }
+
+    fn write_tag_set(&mut self, tag_set_seq: u64, tags: &[(String, String)]) -> io::Result<()> {
+        self.payload.clear();
+        self.payload.extend_from_slice(&tag_set_seq.to_le_bytes());
+        self.payload
+            .extend_from_slice(&(tags.len() as u32).to_le_bytes());
+        for (key, value) in tags {
+            write_str(&mut self.payload, key)?;
+            write_str(&mut self.payload, value)?;
+        }
+
+        self.write_record(RECORD_TAG_SET)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct TextWriter<W: Write> {
+    inner: W,
+    pid: u32,
+}
+
+impl<W: Write> TextWriter<W> {
+    pub fn new(inner: W) -> Self {
+        TextWriter {
+            inner,
+            pid: std::process::id(),
+        }
+    }
+}
+
+impl<W: Write> RecordWriter for TextWriter<W> {
+    fn write_header(
+        &mut self,
+        _clock_source: ClockSource,
+        pid: u32,
+        _ruby_version: &str,
+    ) -> io::Result<()> {
+        self.pid = pid;
+        Ok(())
+    }
+
+    fn write_sample(&mut self, header: &SampleHeader, frames: &[u64]) -> io::Result<()> {
+        write!(
+            self.inner,
+            "[{}][stack_frames][{}, {}",
+            self.pid, header.thread_id, header.ts
+        )?;
+        for frame in frames {
+            write!(self.inner, ", {}", frame)?;
+        }
+        writeln!(self.inner, ", {}, {}]", u64::MAX, u64::MAX)
+    }
+
+    fn write_sample_with_pcs(&mut self, header: &SampleHeader, words: &[u64]) -> io::Result<()> {
+        write!(
+            self.inner,
+            "[{}][stack_frames][{}, {}",
+            self.pid, header.thread_id, header.ts
+        )?;
+        for frame in words.iter().step_by(2) {
+            write!(self.inner, ", {}", frame)?;
+        }
+        writeln!(self.inner, ", {}, {}]", u64::MAX, u64::MAX)
+    }
+
+    fn write_symbol(
+        &mut self,
+        iseq_addr: u64,
+        label: &str,
+        path: &str,
+        _first_lineno: u32,
+    ) -> io::Result<()> {
+        writeln!(
+            self.inner,
+            "[{}][symbol]{}, {}, {}",
+            self.pid, iseq_addr, label, path
+        )
+    }
+
+    fn write_line(&mut self, _iseq_addr: u64, _pc: u64, _lineno: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_dropped(&mut self, samples_count: u64) -> io::Result<()> {
+        writeln!(self.inner, "[{}][dropped]{}", self.pid, samples_count)
+    }
+
+    fn write_trace(
+        &mut self,
+        _trace_seq: u64,
+        _thread_id: u64,
+        _begin_ts: u64,
+        _trace_id: &str,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_trace_end(
+        &mut self,
+        _trace_seq: u64,
+        _end_ts: u64,
+        _gvl_wait_nanos: u64,
+        _gvl_run_nanos: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_tag_set(&mut self, _tag_set_seq: u64, _tags: &[(String, String)]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[inline]
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
@@ -1,9 +1,15 @@
+use crate::aggregator::*;
+use crate::capture::*;
+use crate::cpu_clock::*;
+use crate::gvl::*;
 use crate::helpers::*;
 use crate::logger::*;
 use crate::ruby_version::*;
+use crate::thread_events::*;
+use crate::thread_state::*;
 
 use chrono::Utc;
-use libc::c_void;
+use libc::{c_void, clockid_t};
 use rb_sys::{
     rb_gc_mark, rb_num2dbl, rb_thread_call_with_gvl, rb_thread_call_without_gvl, Qnil, Qtrue,
     RARRAY_LEN, VALUE,
@@ -12,7 +18,7 @@ use rb_sys::{
 use sysinfo::System;
 
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{ptr, thread};
 
 use lazy_static::lazy_static;
@@ -21,6 +27,15 @@ use std::sync;
 use std::sync::Condvar;
 
 const ONE_MILLISECOND_NS: u64 = 1_000_000; // 1ms in nanoseconds
+const USER_PAUSE_CHECK_INTERVAL_MS: u64 = 10;
+// an epoch ends at least this often, besides at GC, so the output is rotated
+// and the aggregated stacks are flushed in a process which rarely GCs.
+// Ending it takes the GVL for symbolizing, about once a second while threads are sampled,
+// it is skipped when nothing was recorded since the previous epoch.
+const EPOCH_MAX_AGE: Duration = Duration::from_secs(1);
+// snapshot ids of captured samples' frames have the top bit, which user space addresses never have,
+// the id without a counter is for frames without symbols
+const SNAPSHOT_ID_BIT: u64 = 1 << 63;
 
 lazy_static! {
     // For using raw mutex in Ruby, we need to release GVL before acquiring the lock.
@@ -33,16 +48,68 @@ lazy_static! {
     pub static ref RUBY_API: RubyAPI = RubyAPI::new(detect_ruby_version());
 }
 
+// The times of a sample, measured by the scanner thread
+#[derive(Clone, Copy)]
+struct SampleTimes {
+    // wall time since the thread's previous sample
+    wall_nanos: u64,
+    // CPU time of the thread since its previous sample, 0 when it isn't known
+    cpu_nanos: u64,
+}
+
 pub struct StackScanner {
     should_stop: bool,
     ecs: Vec<VALUE>,
     rb_thread_ids: Vec<u64>,
     threads: Vec<VALUE>,
+    // the default sampling interval, for threads without their own interval
     sleep_nanos: u64,
+    // sampling interval of each thread, None means the default one
+    thread_sleep_nanos: Vec<Option<u64>>,
+    next_sample_at: Vec<Instant>,
+    // CPU clock of each thread, None when it isn't available
+    cpu_clocks: Vec<Option<clockid_t>>,
+    // CPU time of each thread at its last sample
+    cpu_nanos: Vec<u64>,
+    // when each thread was last sampled, None before its first sample
+    sampled_at: Vec<Option<Instant>>,
     logger: Logger,
+    // paused by GC
     pause: bool,
+    // paused by Sdb.pause, it is kept until Sdb.resume
+    user_pause: bool,
     iseq_buffer: HashSet<u64>,
     translated_iseq: HashMap<u64, bool>,
+    // iseq => the method entry of its method, 0 when it isn't in a method.
+    // They are marked with translated iseqs, as symbols are translated again in later epochs.
+    method_entries: HashMap<u64, u64>,
+    // records pc of each frame, which is translated to line at symbolization
+    record_lines: bool,
+    pc_buffer: HashSet<(u64, u64)>,
+    translated_pcs: HashSet<(u64, u64)>,
+    // when the current epoch began, at the previous consume_iseq_buffer
+    epoch_started_at: Instant,
+    // aggregates samples instead of logging them when it is enabled
+    aggregator: Aggregator,
+    // the thread id and iseq addresses of the sample being aggregated
+    stack_buffer: Vec<u64>,
+    // native thread id => (trace_seq, begin_ts, trace_id) of the request it is serving,
+    // samples are tagged with the trace_seq
+    thread_traces: HashMap<u64, (u64, u64, String)>,
+    last_trace_seq: u64,
+    // native thread id => (tags sorted by key, tag_set_seq) set by Sdb.tag
+    thread_tags: HashMap<u64, (Vec<(String, String)>, u64)>,
+    // a tag set is logged once and then samples only have its tag_set_seq
+    tag_sets: HashMap<Vec<(String, String)>, u64>,
+    // slow request capture, it is on when there is a config
+    capture: Option<CaptureConfig>,
+    // native thread id => samples of the request being captured
+    captured_samples: HashMap<u64, CaptureBuffer>,
+    last_snapshot_id: u64,
+    // symbols of captured samples logged in the current epoch, for its symbol batch
+    pending_snapshot: SymbolSnapshot,
+    // the state of each logged sample's thread
+    thread_states: ThreadStates,
 }
 
 impl StackScanner {
@@ -53,10 +120,32 @@ impl StackScanner {
             rb_thread_ids: Vec::new(),
             threads: Vec::new(),
             sleep_nanos: 0,
+            thread_sleep_nanos: Vec::new(),
+            next_sample_at: Vec::new(),
+            cpu_clocks: Vec::new(),
+            cpu_nanos: Vec::new(),
+            sampled_at: Vec::new(),
             logger: Logger::new(),
             pause: false,
+            user_pause: false,
             iseq_buffer: HashSet::new(),
             translated_iseq: HashMap::new(),
+            method_entries: HashMap::new(),
+            record_lines: false,
+            pc_buffer: HashSet::new(),
+            translated_pcs: HashSet::new(),
+            epoch_started_at: Instant::now(),
+            aggregator: Aggregator::new(),
+            stack_buffer: Vec::new(),
+            thread_traces: HashMap::new(),
+            last_trace_seq: 0,
+            thread_tags: HashMap::new(),
+            tag_sets: HashMap::new(),
+            capture: None,
+            captured_samples: HashMap::new(),
+            last_snapshot_id: 0,
+            pending_snapshot: SymbolSnapshot::default(),
+            thread_states: ThreadStates::new(),
         }
     }
 
@@ -75,6 +164,70 @@ impl StackScanner {
         self.pause
     }
 
+    #[inline]
+    pub fn user_pause(&mut self) {
+        self.user_pause = true;
+    }
+
+    #[inline]
+    pub fn user_resume(&mut self) {
+        self.user_pause = false;
+        // the paused time isn't charged to the next samples
+        self.sampled_at.fill(None);
+        for (cpu_nanos, clock) in self.cpu_nanos.iter_mut().zip(&self.cpu_clocks) {
+            *cpu_nanos = read_cpu_nanos(*clock);
+        }
+    }
+
+    #[inline]
+    pub fn is_user_paused(&self) -> bool {
+        self.user_pause
+    }
+
+    // Prepares for scanning again after the scanner has been stopped
+    #[inline]
+    pub fn reset(&mut self) {
+        self.should_stop = false;
+        self.user_pause = false;
+        // line records are written to the new sample file again
+        self.translated_pcs.clear();
+        self.epoch_started_at = Instant::now();
+    }
+
+    // The child of fork only has the forking thread, the scanner thread and the writer threads
+    // of the parent are gone. It forgets them and the parent's threads, samples and requests,
+    // Sdb starts scanning again in the child with a new output.
+    pub fn after_fork(&mut self) {
+        self.should_stop = true;
+        self.pause = false;
+        self.logger.abandon_after_fork();
+        self.aggregator.abandon_after_fork();
+
+        self.threads.clear();
+        self.ecs.clear();
+        self.rb_thread_ids.clear();
+        self.thread_sleep_nanos.clear();
+        self.next_sample_at.clear();
+        self.cpu_clocks.clear();
+        self.cpu_nanos.clear();
+        self.sampled_at.clear();
+        self.thread_states.clear();
+        self.thread_states.clear_gvl_states();
+        free_gvl_slots_after_fork();
+
+        self.iseq_buffer.clear();
+        self.pc_buffer.clear();
+        self.translated_pcs.clear();
+        self.stack_buffer.clear();
+
+        // native thread ids of the parent mean nothing in the child,
+        // tag sets are kept as they are logged to every new output
+        self.thread_traces.clear();
+        self.thread_tags.clear();
+        self.captured_samples.clear();
+        self.pending_snapshot.clear();
+    }
+
     #[inline]
     pub fn stop(&mut self) {
         self.should_stop = true;
@@ -86,61 +239,407 @@ impl StackScanner {
         self.should_stop
     }
 
+    #[inline]
+    pub fn set_record_lines(&mut self, record_lines: bool) {
+        self.record_lines = record_lines;
+        self.logger.set_with_pcs(record_lines);
+    }
+
+    #[inline]
+    pub fn logger_mut(&mut self) -> &mut Logger {
+        &mut self.logger
+    }
+
+    // A thread serves one request at a time, a new request replaces the previous one.
+    // A captured request is logged when it ends, if it is kept.
+    // gvl is the GVL times of the previous request, if there is one.
+    pub fn begin_trace(&mut self, thread_id: u64, trace_id: &str, gvl: GvlTimes) {
+        self.end_trace(thread_id, None, gvl);
+
+        self.last_trace_seq += 1;
+        let ts = Utc::now().timestamp_micros() as u64;
+        match self.capture.as_ref() {
+            Some(capture) => {
+                self.captured_samples
+                    .insert(thread_id, CaptureBuffer::new(capture.max_samples));
+            }
+            None => self
+                .logger
+                .log_trace_begin(self.last_trace_seq, thread_id, ts, trace_id),
+        }
+        self.thread_traces
+            .insert(thread_id, (self.last_trace_seq, ts, trace_id.to_string()));
+    }
+
+    // status is the response status, such as 200, if there is one,
+    // gvl is how long the request waited for and ran with the GVL
+    pub fn end_trace(&mut self, thread_id: u64, status: Option<i64>, gvl: GvlTimes) {
+        let (trace_seq, begin_ts, trace_id) = match self.thread_traces.remove(&thread_id) {
+            Some(trace) => trace,
+            None => return,
+        };
+        let ts = Utc::now().timestamp_micros() as u64;
+
+        if let Some(samples) = self.captured_samples.remove(&thread_id) {
+            let duration = Duration::from_micros(ts.saturating_sub(begin_ts));
+            let keep = self
+                .capture
+                .as_ref()
+                .map_or(true, |capture| capture.should_keep(duration, status));
+            if !keep {
+                return;
+            }
+
+            self.logger
+                .log_trace_begin(trace_seq, thread_id, begin_ts, &trace_id);
+            samples.write_to(&mut self.logger, &mut self.pending_snapshot);
+        }
+
+        self.logger.log_trace_end(trace_seq, ts, gvl);
+    }
+
+    // Requests being captured are kept when the capture is turned off
+    pub fn set_capture(&mut self, capture: Option<CaptureConfig>) {
+        self.capture = capture;
+        if self.capture.is_some() {
+            return;
+        }
+
+        for (thread_id, samples) in std::mem::take(&mut self.captured_samples) {
+            if let Some((trace_seq, begin_ts, trace_id)) = self.thread_traces.get(&thread_id) {
+                self.logger
+                    .log_trace_begin(*trace_seq, thread_id, *begin_ts, trace_id);
+                samples.write_to(&mut self.logger, &mut self.pending_snapshot);
+            }
+        }
+    }
+
+    // Tags the thread's samples with key=value, until it is untagged
+    pub fn tag(&mut self, thread_id: u64, key: &str, value: &str) {
+        let mut tags = match self.thread_tags.get(&thread_id) {
+            Some((tags, _)) => tags.clone(),
+            None => Vec::new(),
+        };
+
+        match tags.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            Ok(i) if tags[i].1 == value => return,
+            Ok(i) => tags[i].1 = value.to_string(),
+            Err(i) => tags.insert(i, (key.to_string(), value.to_string())),
+        }
+
+        self.set_thread_tags(thread_id, tags);
+    }
+
+    pub fn untag(&mut self, thread_id: u64, key: &str) {
+        let mut tags = match self.thread_tags.get(&thread_id) {
+            Some((tags, _)) => tags.clone(),
+            None => return,
+        };
+
+        if let Ok(i) = tags.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            tags.remove(i);
+            self.set_thread_tags(thread_id, tags);
+        }
+    }
+
+    #[inline]
+    pub fn clear_tags(&mut self, thread_id: u64) {
+        self.thread_tags.remove(&thread_id);
+    }
+
+    #[inline]
+    pub fn remove_gvl_state(&mut self, thread_id: u64) {
+        self.thread_states.remove_gvl_state(thread_id);
+    }
+
+    fn set_thread_tags(&mut self, thread_id: u64, tags: Vec<(String, String)>) {
+        if tags.is_empty() {
+            self.thread_tags.remove(&thread_id);
+            return;
+        }
+
+        let tag_set_seq = match self.tag_sets.get(&tags) {
+            Some(tag_set_seq) => *tag_set_seq,
+            None => {
+                let tag_set_seq = self.tag_sets.len() as u64 + 1;
+                self.logger.log_tag_set(tag_set_seq, &tags);
+                self.tag_sets.insert(tags.clone(), tag_set_seq);
+                tag_set_seq
+            }
+        };
+
+        self.thread_tags.insert(thread_id, (tags, tag_set_seq));
+    }
+
+    // Requests in progress and tag sets are logged again to a newly opened output
+    pub fn log_thread_contexts(&mut self) {
+        for (thread_id, (trace_seq, ts, trace_id)) in &self.thread_traces {
+            // captured requests are logged when they end
+            if self.captured_samples.contains_key(thread_id) {
+                continue;
+            }
+
+            self.logger
+                .log_trace_begin(*trace_seq, *thread_id, *ts, trace_id);
+        }
+
+        for (tags, tag_set_seq) in &self.tag_sets {
+            self.logger.log_tag_set(*tag_set_seq, tags);
+        }
+    }
+
+    #[inline]
+    pub fn aggregator_mut(&mut self) -> &mut Aggregator {
+        &mut self.aggregator
+    }
+
     #[inline]
     pub fn mark_iseqs(&mut self) {
         unsafe {
             for (iseq, _) in &self.translated_iseq {
                 rb_gc_mark(*iseq);
             }
+
+            for (_, me) in &self.method_entries {
+                if *me != 0 {
+                    rb_gc_mark(*me);
+                }
+            }
+        }
+    }
+
+    // The threads to scan are marked, so their rb_thread_t and ec are not freed while they are scanned
+    #[inline]
+    pub fn mark_threads(&mut self) {
+        unsafe {
+            for thread in &self.threads {
+                rb_gc_mark(*thread);
+            }
         }
     }
 
     #[inline]
     pub fn consume_iseq_buffer(&mut self) {
+        // hand over samples before their symbols, so each symbol batch closes the samples before it,
+        // sdb-decode relies on this for resolving reused iseq addresses.
+        self.logger.end_epoch_samples();
+
+        // symbols of the epoch's iseqs, for the snapshots of captured samples
+        let mut epoch_symbols: HashMap<u64, Symbol> = HashMap::new();
+        let snapshot_needed = !self.captured_samples.is_empty();
+
         unsafe {
             for iseq in self.iseq_buffer.drain() {
                 let iseq_ptr = iseq as usize as *const c_void;
 
+                // CFUNC frames are recorded by their method entries
+                if RUBY_API.is_method_entry_imemo(iseq_ptr) {
+                    let (owner_str, method_str, singleton) = RUBY_API.get_method_entry_info(iseq);
+                    let label = method_label(owner_str, method_str, singleton);
+
+                    self.logger.log_symbol(iseq, &label, "", 0);
+                    self.aggregator.add_symbol(iseq, &label, "");
+                    self.translated_iseq.insert(iseq, true);
+                    if snapshot_needed {
+                        epoch_symbols.insert(iseq, (label, "".to_string(), 0));
+                    }
+                    continue;
+                }
+
                 // Ruby VM pushes non-IMEMO_ISEQ iseqs to the frame,
                 // such as captured->code.ifunc in vm_yield_with_cfunc func,
                 // we do not handle those for now.
                 if !RUBY_API.is_iseq_imemo(iseq_ptr) {
+                    // the address may be reused by an iseq after GC
+                    self.method_entries.remove(&iseq);
                     continue;
                 }
 
                 let (label_str, path_str) = RUBY_API.get_iseq_info(iseq);
+                let first_lineno = RUBY_API.get_first_lineno_num(iseq);
+                let me = self.method_entries.get(&iseq).copied().unwrap_or(0);
+                let label = qualified_label(label_str.unwrap_or("".to_string()), me);
+                let path = path_str.unwrap_or("".to_string());
 
-                Logger::log_symbol(&format!(
-                    "{}, {}, {}",
-                    iseq,
-                    label_str.unwrap_or("".to_string()),
-                    path_str.unwrap_or("".to_string())
-                ));
+                self.logger.log_symbol(iseq, &label, &path, first_lineno);
+                self.aggregator.add_symbol(iseq, &label, &path);
                 self.translated_iseq.insert(iseq, true);
+                if snapshot_needed {
+                    epoch_symbols.insert(iseq, (label, path, first_lineno));
+                }
+            }
+
+            for (iseq, pc) in self.pc_buffer.drain() {
+                if pc == 0 || self.translated_pcs.contains(&(iseq, pc)) {
+                    continue;
+                }
+
+                if !RUBY_API.is_iseq_imemo(iseq as usize as *const c_void) {
+                    continue;
+                }
+
+                let lineno = RUBY_API.get_lineno(iseq, pc);
+                self.logger.log_line(iseq, pc, lineno);
+                self.translated_pcs.insert((iseq, pc));
+            }
+
+            self.snapshot_captured_samples(&epoch_symbols);
+            for (id, (label, path, first_lineno)) in self.pending_snapshot.symbols.drain() {
+                self.logger.log_symbol(id, &label, &path, first_lineno);
+            }
+            for ((id, pc), lineno) in self.pending_snapshot.lines.drain() {
+                self.logger.log_line(id, pc, lineno);
             }
 
-            self.logger.flush();
+            self.aggregator.end_epoch();
+            // the next epoch goes to a new file or connection, which needs its own lines
+            if self.logger.end_epoch() {
+                self.translated_pcs.clear();
+            }
+        }
+
+        self.epoch_started_at = Instant::now();
+    }
+
+    // Captured samples of requests in progress may be logged in a later epoch, after GC may have
+    // reused their iseq addresses, so the addresses are replaced with snapshot ids at each epoch end.
+    // An iseq has the same id in all requests within an epoch. GVL must be hold.
+    unsafe fn snapshot_captured_samples(&mut self, epoch_symbols: &HashMap<u64, Symbol>) {
+        let with_pcs = self.record_lines;
+        let last_snapshot_id = &mut self.last_snapshot_id;
+        let mut ids: HashMap<u64, u64> = HashMap::new();
+
+        for samples in self.captured_samples.values_mut() {
+            samples.snapshot_epoch(with_pcs, |iseq, pc| {
+                let symbol = match epoch_symbols.get(&iseq) {
+                    Some(symbol) => symbol,
+                    None => return (SNAPSHOT_ID_BIT, None, None),
+                };
+                let id = *ids.entry(iseq).or_insert_with(|| {
+                    *last_snapshot_id += 1;
+                    SNAPSHOT_ID_BIT | *last_snapshot_id
+                });
+                let lineno = if pc != 0 && RUBY_API.is_iseq_imemo(iseq as usize as *const c_void) {
+                    Some(RUBY_API.get_lineno(iseq, pc))
+                } else {
+                    None
+                };
+
+                (id, Some(symbol), lineno)
+            });
+        }
+    }
+
+    // The scanner thread ends an epoch with the GVL when it is due.
+    // Method entries are only recorded with iseqs, so the iseq buffer tells whether
+    // the epoch has anything to symbolize.
+    #[inline]
+    fn is_epoch_due(&self) -> bool {
+        if self.logger.is_reopen_due() {
+            return true;
+        }
+
+        let recorded = !self.iseq_buffer.is_empty()
+            || !self.pc_buffer.is_empty()
+            || !self.pending_snapshot.symbols.is_empty();
+        recorded && self.epoch_started_at.elapsed() >= EPOCH_MAX_AGE
+    }
+
+    // Stops scanning an exited thread, it doesn't need the GVL
+    pub fn remove_thread(&mut self, rb_thread_id: u64) {
+        while let Some(i) = self.rb_thread_ids.iter().position(|id| *id == rb_thread_id) {
+            self.remove_thread_at(i);
+        }
+    }
+
+    fn remove_thread_at(&mut self, i: usize) {
+        self.threads.remove(i);
+        self.ecs.remove(i);
+        let rb_thread_id = self.rb_thread_ids.remove(i);
+        self.thread_states.remove(rb_thread_id);
+        self.thread_sleep_nanos.remove(i);
+        self.next_sample_at.remove(i);
+        self.cpu_clocks.remove(i);
+        self.cpu_nanos.remove(i);
+        self.sampled_at.remove(i);
+    }
+
+    // Measures the times of the i-th thread's sample taken at now.
+    // The wall time is since its last sample, or its sampling interval for the first one.
+    // The CPU delta is 0 when either CPU time is unknown.
+    #[inline]
+    fn sample_times(&mut self, i: usize, now: Instant, interval: u64) -> SampleTimes {
+        let wall_nanos = match self.sampled_at[i].replace(now) {
+            Some(sampled_at) => now.saturating_duration_since(sampled_at).as_nanos() as u64,
+            None => interval,
+        };
+
+        let cpu_nanos = read_cpu_nanos(self.cpu_clocks[i]);
+        let last_cpu_nanos = std::mem::replace(&mut self.cpu_nanos[i], cpu_nanos);
+        let cpu_nanos = if cpu_nanos == 0 || last_cpu_nanos == 0 {
+            0
+        } else {
+            cpu_nanos.saturating_sub(last_cpu_nanos)
+        };
+
+        SampleTimes {
+            wall_nanos,
+            cpu_nanos,
         }
     }
 
     // GVL must be hold before calling this function
-    pub unsafe fn update_threads(&mut self, threads_to_scan: VALUE, current_thread: VALUE) {
+    // intervals is an array of sampling intervals in seconds for each thread, nil for the default one
+    pub unsafe fn update_threads(
+        &mut self,
+        threads_to_scan: VALUE,
+        intervals: VALUE,
+        current_thread: VALUE,
+    ) {
         let threads_count = RARRAY_LEN(threads_to_scan) as isize;
         self.threads = [].to_vec();
         self.ecs = [].to_vec();
         self.rb_thread_ids = [].to_vec();
+        self.thread_sleep_nanos = [].to_vec();
+        self.next_sample_at = [].to_vec();
+        self.cpu_clocks = [].to_vec();
+        self.cpu_nanos = [].to_vec();
+        self.sampled_at = [].to_vec();
+        self.thread_states.clear();
 
+        let now = Instant::now();
         let mut i: isize = 0;
         while i < threads_count {
             let thread = rb_sys::rb_ary_entry(threads_to_scan, i as i64);
 
-            if thread != current_thread && thread != (Qnil as VALUE) {
+            if thread != current_thread
+                && thread != (Qnil as VALUE)
+                && !RUBY_API.is_thread_killed(thread)
+            {
                 self.threads.push(thread);
                 let ec = RUBY_API.get_ec_from_thread(thread);
                 self.ecs.push(ec as VALUE);
 
                 let rb_thread_id = rb_native_thread_id(thread);
                 self.rb_thread_ids.push(rb_thread_id);
+
+                let interval = if rb_sys::NIL_P(intervals) {
+                    Qnil as VALUE
+                } else {
+                    rb_sys::rb_ary_entry(intervals, i as i64)
+                };
+                if rb_sys::NIL_P(interval) {
+                    self.thread_sleep_nanos.push(None);
+                } else {
+                    self.thread_sleep_nanos
+                        .push(Some(seconds_to_nanos(rb_num2dbl(interval))));
+                }
+                self.next_sample_at.push(now);
+
+                let cpu_clock = thread_cpu_clock(rb_thread_id);
+                self.cpu_clocks.push(cpu_clock);
+                self.cpu_nanos.push(read_cpu_nanos(cpu_clock));
+                self.sampled_at.push(None);
             }
 
             i += 1;
@@ -148,33 +647,246 @@ impl StackScanner {
     }
 }
 
+// Owner#method for instance methods and Owner.method for singleton methods,
+// such as IO#read and File.read
+pub(crate) fn method_label(
+    owner: Option<String>,
+    method: Option<String>,
+    singleton: bool,
+) -> String {
+    format!(
+        "{}{}{}",
+        owner.unwrap_or("<anonymous>".to_string()),
+        if singleton { "." } else { "#" },
+        method.unwrap_or("".to_string())
+    )
+}
+
+// Qualifies an iseq's label by the method entry of its method,
+// `call` becomes `Foo::Bar#call` and `block in call` becomes `block in Foo::Bar#call`.
+// Other labels, such as `<class:Foo>`, are kept. GVL must be hold.
+pub(crate) unsafe fn qualified_label(label: String, me_addr: u64) -> String {
+    if !RUBY_API.is_method_entry_imemo(me_addr as usize as *const c_void) {
+        return label;
+    }
+
+    let (owner_str, method_str, singleton) = RUBY_API.get_method_entry_info(me_addr);
+    let method = match method_str {
+        Some(method) => method,
+        None => return label,
+    };
+
+    if label == method {
+        method_label(owner_str, Some(method), singleton)
+    } else if let Some(prefix) = label.strip_suffix(&format!(" in {}", method)) {
+        format!(
+            "{} in {}",
+            prefix,
+            method_label(owner_str, Some(method.clone()), singleton)
+        )
+    } else {
+        label
+    }
+}
+
+// Looks up the method entry only the first time an iseq is seen
 #[inline]
-// Caller needs to guarantee the thread is alive until the end of this function
+unsafe fn record_method_entry(
+    stack_scanner: &mut StackScanner,
+    iseq_addr: u64,
+    frame: *const c_void,
+) {
+    if !stack_scanner.method_entries.contains_key(&iseq_addr) {
+        let me = RUBY_API.get_frame_method_entry(frame);
+        stack_scanner.method_entries.insert(iseq_addr, me);
+    }
+}
+
+#[inline]
+// Caller needs to hold the scanner lock and check the thread isn't killed.
+// The thread is marked, so its ec is never freed while it is scanned. Its vm stack is on the native
+// thread's stack, and the thread is removed under the lock before the native thread ends, by the
+// thread exit hook, or by Sdb.thread_deleted before Ruby 3.2. A vm stack cleared in between is skipped.
 unsafe extern "C" fn record_thread_frames(
     ec_val: VALUE,
+    thread: VALUE,
     rb_thread_id: VALUE,
+    times: SampleTimes,
     stack_scanner: &mut StackScanner,
 ) -> bool {
+    if stack_scanner.aggregator.is_enabled() {
+        return aggregate_thread_frames(
+            ec_val,
+            rb_thread_id,
+            times.wall_nanos,
+            times.cpu_nanos,
+            stack_scanner,
+        );
+    }
+
+    if stack_scanner.capture.is_some() {
+        return capture_thread_frames(ec_val, thread, rb_thread_id, times, stack_scanner);
+    }
+
     let ts = Utc::now().timestamp_micros();
+    let trace_seq = stack_scanner
+        .thread_traces
+        .get(&(rb_thread_id as u64))
+        .map_or(0, |(trace_seq, _, _)| *trace_seq);
+    let tag_set_seq = stack_scanner
+        .thread_tags
+        .get(&(rb_thread_id as u64))
+        .map_or(0, |(_, tag_set_seq)| *tag_set_seq);
+    let thread_state = stack_scanner
+        .thread_states
+        .classify(thread, rb_thread_id as u64);
     stack_scanner.logger.push(rb_thread_id as u64);
     stack_scanner.logger.push(ts as u64);
+    stack_scanner.logger.push(trace_seq);
+    stack_scanner.logger.push(tag_set_seq);
+    stack_scanner.logger.push(thread_state as u64);
+    stack_scanner.logger.push(times.cpu_nanos);
+    stack_scanner.logger.push(times.wall_nanos);
 
-    // Use the new closure-based API
-    let mut frame_handler = |iseq_addr: u64| {
-        if iseq_addr == 0 {
-            return;
-        } else {
-            stack_scanner.iseq_buffer.insert(iseq_addr);
-            stack_scanner.logger.push(iseq_addr);
-        }
-    };
+    if stack_scanner.record_lines {
+        let mut frame_handler = |iseq_addr: u64, pc: u64, frame: *const c_void| {
+            if iseq_addr == 0 {
+                return;
+            } else {
+                stack_scanner.iseq_buffer.insert(iseq_addr);
+                record_method_entry(stack_scanner, iseq_addr, frame);
+                stack_scanner.pc_buffer.insert((iseq_addr, pc));
+                stack_scanner.logger.push(iseq_addr);
+                stack_scanner.logger.push(pc);
+            }
+        };
+
+        RUBY_API.iterate_frames(ec_val, &mut frame_handler);
+    } else {
+        // Use the new closure-based API
+        let mut frame_handler = |iseq_addr: u64, frame: *const c_void| {
+            if iseq_addr == 0 {
+                return;
+            } else {
+                stack_scanner.iseq_buffer.insert(iseq_addr);
+                record_method_entry(stack_scanner, iseq_addr, frame);
+                stack_scanner.logger.push(iseq_addr);
+            }
+        };
 
-    RUBY_API.iterate_frame_iseqs(ec_val, &mut frame_handler);
+        RUBY_API.iterate_frame_iseqs(ec_val, &mut frame_handler);
+    }
     stack_scanner.logger.push_seperator();
 
     true
 }
 
+// Lines are not aggregated, a stack is only its iseqs
+#[inline]
+unsafe fn aggregate_thread_frames(
+    ec_val: VALUE,
+    rb_thread_id: VALUE,
+    wall_nanos: u64,
+    cpu_nanos: u64,
+    stack_scanner: &mut StackScanner,
+) -> bool {
+    let mut stack = std::mem::take(&mut stack_scanner.stack_buffer);
+    stack.clear();
+    stack.push(rb_thread_id as u64);
+
+    collect_frames(ec_val, false, &mut stack, stack_scanner);
+    stack_scanner.aggregator.add(&stack, wall_nanos, cpu_nanos);
+    stack_scanner.stack_buffer = stack;
+
+    true
+}
+
+// Only threads serving a request are scanned, their samples are held until the request ends
+#[inline]
+unsafe fn capture_thread_frames(
+    ec_val: VALUE,
+    thread: VALUE,
+    rb_thread_id: VALUE,
+    times: SampleTimes,
+    stack_scanner: &mut StackScanner,
+) -> bool {
+    let thread_id = rb_thread_id as u64;
+    if !stack_scanner.captured_samples.contains_key(&thread_id) {
+        return true;
+    }
+
+    let trace_seq = stack_scanner
+        .thread_traces
+        .get(&thread_id)
+        .map_or(0, |(trace_seq, _, _)| *trace_seq);
+    let tag_set_seq = stack_scanner
+        .thread_tags
+        .get(&thread_id)
+        .map_or(0, |(_, tag_set_seq)| *tag_set_seq);
+    let thread_state = stack_scanner.thread_states.classify(thread, thread_id);
+
+    let mut sample = std::mem::take(&mut stack_scanner.stack_buffer);
+    sample.clear();
+    sample.push(thread_id);
+    sample.push(Utc::now().timestamp_micros() as u64);
+    sample.push(trace_seq);
+    sample.push(tag_set_seq);
+    sample.push(thread_state as u64);
+    sample.push(times.cpu_nanos);
+    sample.push(times.wall_nanos);
+
+    collect_frames(
+        ec_val,
+        stack_scanner.record_lines,
+        &mut sample,
+        stack_scanner,
+    );
+    if let Some(samples) = stack_scanner.captured_samples.get_mut(&thread_id) {
+        samples.push_sample(&sample);
+    }
+    stack_scanner.stack_buffer = sample;
+
+    true
+}
+
+// Appends iseq addresses of the frames, or iseq address and pc pairs with_pcs
+#[inline]
+unsafe fn collect_frames(
+    ec_val: VALUE,
+    with_pcs: bool,
+    words: &mut Vec<u64>,
+    stack_scanner: &mut StackScanner,
+) {
+    if with_pcs {
+        let mut frame_handler = |iseq_addr: u64, pc: u64, frame: *const c_void| {
+            if iseq_addr != 0 {
+                stack_scanner.iseq_buffer.insert(iseq_addr);
+                record_method_entry(stack_scanner, iseq_addr, frame);
+                stack_scanner.pc_buffer.insert((iseq_addr, pc));
+                words.push(iseq_addr);
+                words.push(pc);
+            }
+        };
+
+        RUBY_API.iterate_frames(ec_val, &mut frame_handler);
+    } else {
+        let mut frame_handler = |iseq_addr: u64, frame: *const c_void| {
+            if iseq_addr != 0 {
+                stack_scanner.iseq_buffer.insert(iseq_addr);
+                record_method_entry(stack_scanner, iseq_addr, frame);
+                words.push(iseq_addr);
+            }
+        };
+
+        RUBY_API.iterate_frame_iseqs(ec_val, &mut frame_handler);
+    }
+}
+
+#[inline]
+fn seconds_to_nanos(seconds: f64) -> u64 {
+    (seconds * 1_000_000_000.0) as u64
+}
+
 extern "C" fn ubf_pull_loop(_: *mut c_void) {
     let mut stack_scanner = STACK_SCANNER.lock();
     stack_scanner.stop();
@@ -211,7 +923,7 @@ unsafe extern "C" fn looping_helper() -> bool {
             return false;
         }
 
-        let len = stack_scanner.ecs.len();
+        let mut len = stack_scanner.ecs.len();
         let sleep_nanos = stack_scanner.sleep_nanos;
 
         if stack_scanner.is_stopped() {
@@ -219,10 +931,51 @@ unsafe extern "C" fn looping_helper() -> bool {
             return true;
         }
 
+        if take_threads_changed() {
+            // Sdb.threads_changed updates the threads to scan, which needs the lock
+            drop(stack_scanner);
+            rb_thread_call_with_gvl(Some(threads_changed_with_gvl), ptr::null_mut());
+            continue;
+        }
+
+        if stack_scanner.is_epoch_due() {
+            // symbols are translated with the GVL, so GC can't free or move iseqs meanwhile
+            drop(stack_scanner);
+            rb_thread_call_with_gvl(Some(consume_iseq_buffer_with_gvl), ptr::null_mut());
+            continue;
+        }
+
+        if stack_scanner.is_user_paused() {
+            drop(stack_scanner);
+            thread::sleep(Duration::from_millis(USER_PAUSE_CHECK_INTERVAL_MS));
+            continue;
+        }
+
+        // only scans the threads whose sampling time has come,
+        // and then sleeps until the earliest next sampling time
+        let now = Instant::now();
+        let mut next_wakeup = now + Duration::from_nanos(sleep_nanos);
+
         while i < len {
-            let ec = stack_scanner.ecs[i];
-            let rb_thread_id = stack_scanner.rb_thread_ids[i];
-            record_thread_frames(ec, rb_thread_id, &mut stack_scanner);
+            // a thread exited without the exit hook, such as a thread started before Sdb is loaded
+            if RUBY_API.is_thread_killed(stack_scanner.threads[i]) {
+                stack_scanner.remove_thread_at(i);
+                len -= 1;
+                continue;
+            }
+
+            if stack_scanner.next_sample_at[i] <= now {
+                let ec = stack_scanner.ecs[i];
+                let thread = stack_scanner.threads[i];
+                let rb_thread_id = stack_scanner.rb_thread_ids[i];
+                let interval = stack_scanner.thread_sleep_nanos[i].unwrap_or(sleep_nanos);
+                let times = stack_scanner.sample_times(i, now, interval);
+                record_thread_frames(ec, thread, rb_thread_id, times, &mut stack_scanner);
+
+                stack_scanner.next_sample_at[i] = now + Duration::from_nanos(interval);
+            }
+
+            next_wakeup = next_wakeup.min(stack_scanner.next_sample_at[i]);
             i += 1;
         }
 
@@ -230,6 +983,10 @@ unsafe extern "C" fn looping_helper() -> bool {
         // as ruby doesn't have many threads normally and stack scanning is very fast.
         drop(stack_scanner);
 
+        let sleep_nanos = next_wakeup
+            .saturating_duration_since(Instant::now())
+            .as_nanos() as u64;
+
         if sleep_nanos < ONE_MILLISECOND_NS {
             // For sub-millisecond sleeps, use busy-wait for more precise timing
             let start = std::time::Instant::now();
@@ -275,7 +1032,7 @@ pub(crate) unsafe extern "C" fn rb_pull(_module: VALUE, sleep_seconds_rb: VALUE)
         sleep_seconds
     );
 
-    let sleep_nanos = (sleep_seconds * 1_000_000_000.0) as u64;
+    let sleep_nanos = seconds_to_nanos(sleep_seconds);
 
     let mut stack_scanner = STACK_SCANNER.lock();
     stack_scanner.sleep_nanos = sleep_nanos;
@@ -293,6 +1050,9 @@ pub(crate) unsafe extern "C" fn rb_pull(_module: VALUE, sleep_seconds_rb: VALUE)
 
     let mut stack_scanner = STACK_SCANNER.lock();
     stack_scanner.consume_iseq_buffer();
+    // writes everything out and reports sample totals
+    stack_scanner.logger.close();
+    stack_scanner.aggregator.close();
 
     Qtrue as VALUE
 }
@@ -307,17 +1067,146 @@ pub(crate) unsafe extern "C" fn rb_log_uptime_and_clock_time(_module: VALUE) ->
 pub(crate) unsafe extern "C" fn rb_update_threads_to_scan(
     module: VALUE,
     threads_to_scan: VALUE,
+    intervals: VALUE,
 ) -> VALUE {
     let argv: &[VALUE; 0] = &[];
     let current_thread = call_method(module, "current_thread", 0, argv);
 
     let mut stack_scanner = STACK_SCANNER.lock();
-    stack_scanner.update_threads(threads_to_scan, current_thread);
+    stack_scanner.update_threads(threads_to_scan, intervals, current_thread);
     drop(stack_scanner);
 
     return Qnil as VALUE;
 }
 
+pub(crate) unsafe extern "C" fn rb_set_record_lines(_module: VALUE, record_lines: VALUE) -> VALUE {
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.set_record_lines(rb_sys::TEST(record_lines));
+
+    return Qnil as VALUE;
+}
+
+pub(crate) unsafe extern "C" fn rb_reset_scanner(_module: VALUE) -> VALUE {
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.reset();
+
+    return Qnil as VALUE;
+}
+
+pub(crate) unsafe extern "C" fn rb_pause_scanner(_module: VALUE) -> VALUE {
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.user_pause();
+
+    return Qnil as VALUE;
+}
+
+pub(crate) unsafe extern "C" fn rb_resume_scanner(_module: VALUE) -> VALUE {
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.user_resume();
+
+    return Qnil as VALUE;
+}
+
+// the new interval takes effect after the threads' next sampling
+pub(crate) unsafe extern "C" fn rb_set_sleep_interval(
+    _module: VALUE,
+    sleep_seconds_rb: VALUE,
+) -> VALUE {
+    let sleep_nanos = seconds_to_nanos(rb_num2dbl(sleep_seconds_rb));
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.sleep_nanos = sleep_nanos;
+
+    return Qnil as VALUE;
+}
+
+// Tags samples of the current thread with trace_id until Sdb.end_request
+pub(crate) unsafe extern "C" fn rb_begin_request(_module: VALUE, trace_id: VALUE) -> VALUE {
+    let trace_id = RUBY_API
+        .ruby_str_to_rust_str(rb_sys::rb_obj_as_string(trace_id))
+        .unwrap_or("".to_string());
+    let thread_id = rb_native_thread_id(rb_sys::rb_thread_current());
+    let gvl = restart_request_gvl_times();
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.begin_trace(thread_id, &trace_id, gvl);
+
+    return Qnil as VALUE;
+}
+
+// status is the response status or nil, see Sdb.end_request
+pub(crate) unsafe extern "C" fn rb_finish_request(_module: VALUE, status: VALUE) -> VALUE {
+    let thread_id = rb_native_thread_id(rb_sys::rb_thread_current());
+    let status = if rb_sys::NIL_P(status) {
+        None
+    } else {
+        Some(rb_sys::rb_num2long(status) as i64)
+    };
+    let gvl = restart_request_gvl_times();
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.end_trace(thread_id, status, gvl);
+
+    return Qnil as VALUE;
+}
+
+// threshold nil turns slow request capture off
+pub(crate) unsafe extern "C" fn rb_set_capture(
+    _module: VALUE,
+    threshold: VALUE,
+    max_samples: VALUE,
+) -> VALUE {
+    let capture = if rb_sys::NIL_P(threshold) {
+        None
+    } else {
+        Some(CaptureConfig {
+            threshold: Duration::from_secs_f64(rb_num2dbl(threshold)),
+            max_samples: rb_sys::rb_num2long(max_samples) as usize,
+        })
+    };
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.set_capture(capture);
+
+    return Qnil as VALUE;
+}
+
+// key and value are converted by to_s, such as Sdb.tag(:tenant, 42)
+pub(crate) unsafe extern "C" fn rb_tag(_module: VALUE, key: VALUE, value: VALUE) -> VALUE {
+    let key = RUBY_API.ruby_str_to_rust_str(rb_sys::rb_obj_as_string(key));
+    let value = RUBY_API.ruby_str_to_rust_str(rb_sys::rb_obj_as_string(value));
+    let thread_id = rb_native_thread_id(rb_sys::rb_thread_current());
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.tag(
+        thread_id,
+        &key.unwrap_or("".to_string()),
+        &value.unwrap_or("".to_string()),
+    );
+
+    return Qnil as VALUE;
+}
+
+pub(crate) unsafe extern "C" fn rb_untag(_module: VALUE, key: VALUE) -> VALUE {
+    let key = RUBY_API.ruby_str_to_rust_str(rb_sys::rb_obj_as_string(key));
+    let thread_id = rb_native_thread_id(rb_sys::rb_thread_current());
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.untag(thread_id, &key.unwrap_or("".to_string()));
+
+    return Qnil as VALUE;
+}
+
+// for a finished thread, as its native thread id may be reused
+pub(crate) unsafe extern "C" fn rb_clear_tags(_module: VALUE, thread: VALUE) -> VALUE {
+    let thread_id = rb_native_thread_id(thread);
+
+    let mut stack_scanner = STACK_SCANNER.lock();
+    stack_scanner.clear_tags(thread_id);
+
+    return Qnil as VALUE;
+}
+
 pub(crate) unsafe extern "C" fn rb_stop_scanner(_module: VALUE) -> VALUE {
     let mut stack_scanner = STACK_SCANNER.lock();
     stack_scanner.stop();
@@ -1,22 +1,61 @@
 use libc::c_void;
-use rb_sys::{rb_ary_new, rb_ary_push, rb_int2inum, rb_num2long, rb_str_new, Qfalse, Qtrue, VALUE};
+use rb_sys::{
+    rb_ary_new, rb_ary_push, rb_int2inum, rb_num2long, rb_str_new, Qfalse, Qnil, Qtrue, VALUE,
+};
 
 pub(crate) unsafe extern "C" fn rb_get_ec_from_thread(_module: VALUE, thread: VALUE) -> VALUE {
     let ec = crate::stack_scanner::RUBY_API.get_ec_from_thread(thread) as isize;
     rb_int2inum(ec)
 }
 
+// [the GVL mutex address, the rb_thread_t holding the GVL, the thread's rb_thread_t]
+pub(crate) unsafe extern "C" fn rb_get_gvl_info(_module: VALUE, thread: VALUE) -> VALUE {
+    let api = &crate::stack_scanner::RUBY_API;
+    let thread_ptr = thread as *mut rb_sys::RTypedData;
+
+    let array = rb_ary_new();
+    rb_ary_push(array, rb_int2inum(api.get_gvl_lock_addr(thread) as isize));
+    rb_ary_push(array, rb_int2inum(api.get_gvl_owner(thread) as isize));
+    rb_ary_push(array, rb_int2inum((*thread_ptr).data as isize));
+    array
+}
+
 pub(crate) unsafe extern "C" fn rb_get_iseqs(_module: VALUE, ec_val: VALUE) -> VALUE {
     let ec = rb_num2long(ec_val) as *const c_void as u64;
     let array = rb_ary_new();
 
-    crate::stack_scanner::RUBY_API.iterate_frame_iseqs(ec, &mut |iseq_addr| {
+    crate::stack_scanner::RUBY_API.iterate_frame_iseqs(ec, &mut |iseq_addr, _frame| {
         rb_ary_push(array, rb_int2inum(iseq_addr as isize));
     });
 
     array
 }
 
+pub(crate) unsafe extern "C" fn rb_get_frames(_module: VALUE, ec_val: VALUE) -> VALUE {
+    let ec = rb_num2long(ec_val) as *const c_void as u64;
+    let array = rb_ary_new();
+
+    crate::stack_scanner::RUBY_API.iterate_frames(ec, &mut |iseq_addr, pc, _frame| {
+        let frame = rb_ary_new();
+        rb_ary_push(frame, rb_int2inum(iseq_addr as isize));
+        rb_ary_push(frame, rb_int2inum(pc as isize));
+        rb_ary_push(array, frame);
+    });
+
+    array
+}
+
+pub(crate) unsafe extern "C" fn rb_get_lineno(
+    _module: VALUE,
+    iseq_val: VALUE,
+    pc_val: VALUE,
+) -> VALUE {
+    let iseq = rb_num2long(iseq_val) as *const c_void as u64;
+    let pc = rb_num2long(pc_val) as *const c_void as u64;
+
+    rb_int2inum(crate::stack_scanner::RUBY_API.get_lineno(iseq, pc) as isize)
+}
+
 pub(crate) unsafe extern "C" fn rb_is_iseq_imemo(_module: VALUE, iseq_val: VALUE) -> VALUE {
     let iseq = rb_num2long(iseq_val) as *const c_void;
 
@@ -45,3 +84,44 @@ pub(crate) unsafe extern "C" fn rb_get_iseq_info(_module: VALUE, iseq_val: VALUE
 
     array
 }
+
+pub(crate) unsafe extern "C" fn rb_get_method_entry_info(_module: VALUE, me_val: VALUE) -> VALUE {
+    let me = rb_num2long(me_val) as *const c_void;
+
+    if !crate::stack_scanner::RUBY_API.is_method_entry_imemo(me) {
+        return Qnil as VALUE;
+    }
+
+    let (owner, method, singleton) =
+        crate::stack_scanner::RUBY_API.get_method_entry_info(me as u64);
+
+    let array = rb_ary_new();
+    rb_ary_push(array, rust_to_ruby_string(&owner.unwrap_or("".to_string())));
+    rb_ary_push(
+        array,
+        rust_to_ruby_string(&method.unwrap_or("".to_string())),
+    );
+    rb_ary_push(array, if singleton { Qtrue } else { Qfalse } as VALUE);
+
+    array
+}
+
+// the labels of the iseq frames, qualified the same as symbols
+pub(crate) unsafe extern "C" fn rb_get_frame_labels(_module: VALUE, ec_val: VALUE) -> VALUE {
+    let ec = rb_num2long(ec_val) as *const c_void as u64;
+    let array = rb_ary_new();
+    let api = &crate::stack_scanner::RUBY_API;
+
+    api.iterate_frame_iseqs(ec, &mut |iseq_addr, frame| {
+        if !api.is_iseq_imemo(iseq_addr as *const c_void) {
+            return;
+        }
+
+        let (label, _) = api.get_iseq_info(iseq_addr);
+        let me = api.get_frame_method_entry(frame);
+        let label = crate::stack_scanner::qualified_label(label.unwrap_or("".to_string()), me);
+        rb_ary_push(array, rust_to_ruby_string(&label));
+    });
+
+    array
+}
@@ -0,0 +1,116 @@
+use crate::gvl::{enable_gvl_hooks, free_gvl_slot, gvl_event_callback};
+use crate::helpers::*;
+use crate::stack_scanner::STACK_SCANNER;
+
+use libc::{c_char, c_int, c_void};
+use rb_sys::{Qfalse, Qnil, Qtrue, VALUE};
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// from ruby/thread.h, thread event hooks are available since Ruby 3.2
+const RUBY_INTERNAL_THREAD_EVENT_STARTED: u32 = 1 << 0;
+pub(crate) const RUBY_INTERNAL_THREAD_EVENT_READY: u32 = 1 << 1;
+pub(crate) const RUBY_INTERNAL_THREAD_EVENT_RESUMED: u32 = 1 << 2;
+pub(crate) const RUBY_INTERNAL_THREAD_EVENT_SUSPENDED: u32 = 1 << 3;
+const RUBY_INTERNAL_THREAD_EVENT_EXITED: u32 = 1 << 4;
+
+type ThreadEventCallback = unsafe extern "C" fn(u32, *const c_void, *mut c_void);
+type AddEventHookFn = unsafe extern "C" fn(ThreadEventCallback, u32, *mut c_void) -> *mut c_void;
+
+static THREADS_CHANGED: AtomicBool = AtomicBool::new(false);
+static SDB_MODULE: AtomicU64 = AtomicU64::new(0);
+
+// Hooks run on the started or exited thread, maybe without the GVL, so Ruby can't be called here.
+// An exited thread is removed from the scanner at once, as its stack is going away,
+// and the scanner thread calls Sdb.threads_changed with the GVL for the filter of new threads.
+unsafe extern "C" fn thread_event_callback(
+    event: u32,
+    _event_data: *const c_void,
+    _user_data: *mut c_void,
+) {
+    if event & RUBY_INTERNAL_THREAD_EVENT_EXITED != 0 {
+        free_gvl_slot();
+        if let Some(thread_id) = current_native_thread_id() {
+            let mut stack_scanner = STACK_SCANNER.lock();
+            stack_scanner.remove_thread(thread_id);
+            // the native thread id may be reused by a new thread
+            stack_scanner.clear_tags(thread_id);
+            stack_scanner.remove_gvl_state(thread_id);
+        }
+    }
+
+    THREADS_CHANGED.store(true, Ordering::Release);
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+pub(crate) fn current_native_thread_id() -> Option<u64> {
+    Some(unsafe { libc::gettid() } as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+pub(crate) fn current_native_thread_id() -> Option<u64> {
+    None
+}
+
+#[inline]
+pub(crate) fn take_threads_changed() -> bool {
+    THREADS_CHANGED.swap(false, Ordering::AcqRel)
+}
+
+unsafe extern "C" fn call_threads_changed(module: VALUE) -> VALUE {
+    let argv: &[VALUE; 0] = &[];
+    call_method(module, "threads_changed", 0, argv)
+}
+
+// It is called by the scanner thread with rb_thread_call_with_gvl
+pub(crate) unsafe extern "C" fn threads_changed_with_gvl(_: *mut c_void) -> *mut c_void {
+    let module = SDB_MODULE.load(Ordering::Acquire) as VALUE;
+    if module == 0 {
+        return ptr::null_mut();
+    }
+
+    // an exception can't go through the scanner thread's frames
+    let mut state: c_int = 0;
+    rb_sys::rb_protect(Some(call_threads_changed), module, &mut state);
+    if state != 0 {
+        rb_sys::rb_set_errinfo(Qnil as VALUE);
+        log::error!("[scanner] Sdb.threads_changed raised an exception");
+    }
+
+    ptr::null_mut()
+}
+
+// Returns false when thread event hooks are not available, before Ruby 3.2
+pub(crate) unsafe extern "C" fn rb_setup_thread_hooks(module: VALUE) -> VALUE {
+    let add_event_hook = libc::dlsym(
+        libc::RTLD_DEFAULT,
+        "rb_internal_thread_add_event_hook\0".as_ptr() as *const c_char,
+    );
+    if add_event_hook.is_null() {
+        return Qfalse as VALUE;
+    }
+
+    if SDB_MODULE.swap(module as u64, Ordering::AcqRel) == 0 {
+        let add_event_hook: AddEventHookFn = std::mem::transmute(add_event_hook);
+        add_event_hook(
+            thread_event_callback,
+            RUBY_INTERNAL_THREAD_EVENT_STARTED | RUBY_INTERNAL_THREAD_EVENT_EXITED,
+            ptr::null_mut(),
+        );
+
+        // GVL events are frequent, they have their own hook for not waking the scanner
+        add_event_hook(
+            gvl_event_callback,
+            RUBY_INTERNAL_THREAD_EVENT_READY
+                | RUBY_INTERNAL_THREAD_EVENT_RESUMED
+                | RUBY_INTERNAL_THREAD_EVENT_SUSPENDED,
+            ptr::null_mut(),
+        );
+        enable_gvl_hooks();
+    }
+
+    Qtrue as VALUE
+}
@@ -0,0 +1,127 @@
+use crate::gvl::{gvl_hooks_enabled, load_gvl_state, GvlState};
+use crate::stack_scanner::RUBY_API;
+
+use rb_sys::{RTypedData, VALUE};
+
+use std::collections::HashMap;
+use std::fs::File;
+
+// What a thread was doing when it was sampled, it is written with each sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ThreadState {
+    Unknown = 0,
+    // on CPU, with the GVL or in a blocking region without it
+    Running = 1,
+    // runnable, but another thread holds the GVL
+    WaitingGvl = 2,
+    // sleeping, waiting for IO or a lock, or blocked in a syscall
+    Blocked = 3,
+}
+
+// Classifies threads by their GVL state from the GVL hooks, or by the thread struct and the GVL
+// owner before Ruby 3.2, and by /proc/self/task/<tid>/stat for telling a thread running
+// without the GVL from a blocked one. The stat files are kept open and read again for each sample.
+pub struct ThreadStates {
+    stat_files: HashMap<u64, File>,
+    // native thread id => index of the slot its GVL hook publishes its state in
+    gvl_slots: HashMap<u64, usize>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    stat_buffer: Vec<u8>,
+}
+
+impl ThreadStates {
+    pub fn new() -> Self {
+        ThreadStates {
+            stat_files: HashMap::new(),
+            gvl_slots: HashMap::new(),
+            stat_buffer: vec![0; 512],
+        }
+    }
+
+    // The caller needs to guarantee the thread isn't killed, see record_thread_frames
+    #[inline]
+    pub unsafe fn classify(&mut self, thread: VALUE, thread_id: u64) -> ThreadState {
+        let gvl_state = if gvl_hooks_enabled() {
+            let index = self.gvl_slots.entry(thread_id).or_insert(usize::MAX);
+            load_gvl_state(thread_id, index)
+        } else {
+            GvlState::Unknown
+        };
+
+        let holds_gvl = match gvl_state {
+            GvlState::Waiting => return ThreadState::WaitingGvl,
+            GvlState::Running => true,
+            GvlState::Suspended => false,
+            GvlState::Unknown => {
+                let thread_addr = (*(thread as *mut RTypedData)).data as u64;
+                let holds_gvl = RUBY_API.get_gvl_owner(thread) == thread_addr;
+                if !holds_gvl && RUBY_API.is_thread_runnable(thread) {
+                    return ThreadState::WaitingGvl;
+                }
+                holds_gvl
+            }
+        };
+
+        // a thread holding the GVL may still be blocked in a syscall, such as a C extension's read
+        match self.task_state(thread_id) {
+            Some(b'R') => ThreadState::Running,
+            Some(_) => ThreadState::Blocked,
+            None if holds_gvl => ThreadState::Running,
+            None => ThreadState::Blocked,
+        }
+    }
+
+    pub fn remove(&mut self, thread_id: u64) {
+        self.stat_files.remove(&thread_id);
+    }
+
+    // native thread ids may be reused, the files are opened again for new threads
+    pub fn clear(&mut self) {
+        self.stat_files.clear();
+    }
+
+    // slot indexes are kept while their threads live, whether they are scanned or not
+    pub fn remove_gvl_state(&mut self, thread_id: u64) {
+        self.gvl_slots.remove(&thread_id);
+    }
+
+    // after fork, the forking thread claims a slot again at its next GVL event
+    pub fn clear_gvl_states(&mut self) {
+        self.gvl_slots.clear();
+    }
+
+    // the state field of the stat file, such as R for running and S for sleeping
+    #[cfg(target_os = "linux")]
+    fn task_state(&mut self, thread_id: u64) -> Option<u8> {
+        use std::os::unix::fs::FileExt;
+
+        if thread_id == 0 {
+            return None;
+        }
+
+        if !self.stat_files.contains_key(&thread_id) {
+            let file = File::open(format!("/proc/self/task/{}/stat", thread_id)).ok()?;
+            self.stat_files.insert(thread_id, file);
+        }
+
+        let file = self.stat_files.get(&thread_id)?;
+        let len = match file.read_at(&mut self.stat_buffer, 0) {
+            Ok(len) => len,
+            Err(_) => {
+                self.stat_files.remove(&thread_id);
+                return None;
+            }
+        };
+
+        // pid (comm) state ..., comm may have spaces and parentheses
+        let stat = &self.stat_buffer[..len];
+        let comm_end = stat.iter().rposition(|byte| *byte == b')')?;
+        stat.get(comm_end + 2).copied()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn task_state(&mut self, _thread_id: u64) -> Option<u8> {
+        None
+    }
+}
@@ -23,8 +23,26 @@ module Sdb
       @initialized = true
       @active_threads = []
       @lock = Mutex.new
+      @window_lock = Mutex.new
       @scan_config = {}
+      @logger_config = {}
+      @aggregation_config = nil
+      @thread_intervals = {}
+      @matched_threads = {}
       self.setup_gc_hooks
+
+      # threads created before sdb is loaded or from C are found by thread event hooks,
+      # older rubies rely on Thread#initialize being patched
+      @thread_hooks = self.setup_thread_hooks
+      @active_threads = Thread.list if @thread_hooks
+
+      # a forked child, such as a Puma, Unicorn, Pitchfork or Resque worker, scans again by itself
+      @scan_in_children = false
+      self.setup_fork_hooks
+    end
+
+    def thread_hooks?
+      @thread_hooks
     end
 
     def current_thread
@@ -39,17 +57,15 @@ module Sdb
       self.pull(threads, 0)
     end
 
-    def start_scan_helper(sleep_interval, &filter)
-      @scan_config = { sleep_interval: sleep_interval, filter: filter }
+    # filter is called with each thread until it returns true for the thread
+    def start_scan_helper(sleep_interval, record_lines: false, &filter)
+      @scan_config = { sleep_interval: sleep_interval, record_lines: record_lines, filter: filter }
+      @matched_threads = {}
+      @scan_in_children = true
 
-      # Don't start thread in master process
+      # Don't start thread in master process, workers start scanning when they are forked
       if puma_detected? && puma_worker_mode?
         config = Puma.cli_config
-        config.options[:before_worker_boot] ||= []
-        config.options[:before_worker_boot] << proc {
-          Sdb.worker_forked!
-        }
-
         config.options[:before_worker_shutdown] ||= []
         config.options[:before_worker_shutdown] << proc {
           Sdb.stop_scanner
@@ -60,39 +76,212 @@ module Sdb
       end
     end
 
-    def scan_all_threads(sleep_interval = 0.001)
-      start_scan_helper(sleep_interval) { true }
+    def scan_all_threads(sleep_interval = 0.001, record_lines: false)
+      start_scan_helper(sleep_interval, record_lines: record_lines) { true }
     end
 
-    def scan_puma_threads(sleep_interval = 0.001)
-      start_scan_helper(sleep_interval) do |thread|
+    def scan_puma_threads(sleep_interval = 0.001, record_lines: false)
+      start_scan_helper(sleep_interval, record_lines: record_lines) do |thread|
         thread.name&.include?('puma srv tp')
       end
     end
 
+    # Samples the thread every interval seconds instead of the scanner's sleep_interval,
+    # nil resets it to the scanner's one.
+    def set_thread_interval(thread, interval)
+      @lock.synchronize do
+        if interval.nil?
+          @thread_intervals.delete(thread)
+        else
+          @thread_intervals[thread] = interval.to_f
+        end
+
+        refresh_threads_to_scan if @scan_config[:filter]
+      end
+    end
+
+    # Starts scanning again after Sdb.stop, with the threads and options of
+    # scan_all_threads or scan_puma_threads. Returns false if it is scanning already.
+    def start(sleep_interval = nil)
+      raise "Sdb.start needs scan_all_threads or scan_puma_threads to be called first" unless @scan_config[:filter]
+      return false if scanning?
+
+      @scan_config[:sleep_interval] = sleep_interval if sleep_interval
+      @scan_in_children = true
+      start_scanning
+
+      true
+    end
+
+    # Stops scanning and waits for the scanner to write out all samples.
+    # Returns false if it isn't scanning.
+    def stop
+      @scan_in_children = false
+      return false unless scanning?
+
+      self.stop_scanner
+      @scanner_thread.join
+
+      true
+    end
+
+    def scanning?
+      !@scanner_thread.nil? && @scanner_thread.alive?
+    end
+
+    # Sdb.pause and Sdb.resume are defined by the extension,
+    # a paused scanner keeps its thread and sample file but doesn't take samples.
+
+    # Sdb.begin_request(trace_id) is defined by the extension, samples of the current thread
+    # until Sdb.end_request are tagged with trace_id, `sdb-decode --trace TRACE_ID` decodes only them.
+    # Sdb.tag(key, value) and Sdb.untag(key) are defined by the extension too,
+    # samples of the current thread are tagged with its tags, such as Sdb.tag(:tenant, tenant.id).
+    # They are cheap enough for every request or job, `sdb-decode --tag KEY=VALUE` decodes only them.
+
+    # Sdb.gvl_times is defined by the extension, it returns [wait, run] seconds of the current thread,
+    # how long it has waited for the GVL and run with it, nil before Ruby 3.2.
+    # The trace end record of a request has them too, `sdb-decode --format requests` reports them.
+
+    # status is the response status, slow request capture keeps 5xx requests
+    def end_request(status = nil)
+      self.finish_request(status&.to_i)
+    end
+
+    # Slow request capture holds samples of each request and only writes them when the request
+    # takes threshold seconds or longer, or ends with a 5xx status, only the latest max_samples
+    # samples of a request are kept. Threads are only sampled while they serve requests,
+    # so a short sleep_interval is affordable. slow_request_mode(false) turns it off.
+    def slow_request_mode(enabled = true, threshold: 0.5, max_samples: 10_000)
+      self.set_capture(enabled ? threshold.to_f : nil, max_samples)
+    end
+
+    def set_interval(sleep_interval)
+      @scan_config[:sleep_interval] = sleep_interval
+      self.set_sleep_interval(sleep_interval)
+    end
+
+    # Configures where samples go, it takes effect when the scanner starts.
+    # sink is :file, :unix (path is the socket's path) or :stdout, format is :binary or :text,
+    # log_path is for sdb's own log. %p in paths is replaced by the pid,
+    # so forked workers write to their own files, such as path: '/var/log/sdb/sdb-%p.bin'.
+    def init_logger(path: nil, format: :binary, sink: :file, log_path: nil)
+      raise ArgumentError, "unknown format: #{format}" unless %i[binary text].include?(format.to_sym)
+      raise ArgumentError, "unknown sink: #{sink}" unless %i[file unix stdout].include?(sink.to_sym)
+      raise ArgumentError, "the unix sink needs the socket's path" if sink.to_sym == :unix && path.nil?
+
+      @logger_config = { path: path, format: format, sink: sink, log_path: log_path }
+    end
+
+    # Continuous mode rotates the sample file when it reaches max_bytes or max_age seconds,
+    # files are named sdb-<pid>-<start time>.bin and only the latest keep files are kept.
+    # The file is checked after each sample buffer, and rotated at the next epoch end, at GC
+    # or within a second, as a file must have the symbols of its samples.
+    # It should be called before scanning.
+    def continuous_mode(max_bytes: 64 * 1024 * 1024, max_age: 3600, keep: 24)
+      self.set_rotation(max_bytes, max_age, keep)
+    end
+
+    # Aggregation mode folds samples into stacks in the extension instead of writing every sample,
+    # path (sdb-<pid>.folded by default) is rewritten every flush_interval seconds with lines of
+    # `thread-<tid>;<root frame>;...;<current frame> <samples> <wall time in micros> <cpu time in micros>`.
+    # Stacks are folded at each epoch end, at GC or within a second, so the file lags them by up to a second.
+    # Lines are not recorded. aggregation_mode(false) writes samples again,
+    # both take effect when the scanner starts.
+    def aggregation_mode(enabled = true, path: nil, flush_interval: 10)
+      @aggregation_config = enabled ? { path: path, flush_interval: flush_interval } : nil
+    end
+
+    # Profiles all threads for duration seconds when the process receives the signal,
+    # samples are written to sdb-<pid>-<timestamp>.bin.
+    def profile_on_signal(signal = 'USR2', duration: 10, sleep_interval: 0.001)
+      Signal.trap(signal) do
+        # locks can't be taken in trap context, so the window runs in its own thread
+        Thread.new do
+          Thread.current.name = "sdb-window-#{Process.pid}"
+          profile_window(duration, sleep_interval)
+        end
+      end
+    end
+
+    # Scans all threads for duration seconds and then stops,
+    # returns false if it is scanning already.
+    def profile_window(duration, sleep_interval = 0.001)
+      @window_lock.synchronize do
+        return false if scanning?
+
+        previous_config = @scan_config
+        previous_logger_config = @logger_config
+        previous_aggregation_config = @aggregation_config
+        previous_scan_in_children = @scan_in_children
+        begin
+          @scan_config = { sleep_interval: sleep_interval, record_lines: false, filter: proc { true } }
+          # a window is only for this process
+          @scan_in_children = false
+          # and always writes samples, even in aggregation mode
+          @aggregation_config = nil
+          @matched_threads = {}
+          if @logger_config.fetch(:sink, :file).to_sym == :file
+            dir = @logger_config[:path] ? File.dirname(@logger_config[:path]) : '.'
+            path = File.join(dir, "sdb-%p-#{Time.now.strftime('%Y%m%d%H%M%S')}.bin")
+            @logger_config = @logger_config.merge(path: path)
+          end
+          start_scanning
+
+          sleep duration
+          stop
+        ensure
+          @scan_config = previous_config
+          @logger_config = previous_logger_config
+          @aggregation_config = previous_aggregation_config
+          @scan_in_children = previous_scan_in_children
+          @matched_threads = {}
+        end
+      end
+
+      true
+    end
+
     def thread_created(thread)
       @lock.synchronize do
         @active_threads << thread
-        if @scan_config[:filter]
-          threads_to_scan = @active_threads.filter(&@scan_config[:filter]).to_a
-          self.update_threads_to_scan(threads_to_scan)
-        end
+        refresh_threads_to_scan if @scan_config[:filter]
       end
     end
 
     def thread_deleted(thread)
       @lock.synchronize do
         @active_threads.delete(thread)
-        if @scan_config[:filter]
-          threads_to_scan = @active_threads.filter(&@scan_config[:filter]).to_a
+        @thread_intervals.delete(thread)
+        self.clear_tags(thread)
+        refresh_threads_to_scan if @scan_config[:filter]
+      end
+    end
 
-          self.update_threads_to_scan(threads_to_scan)
-        end
+    # Called by the scanner when threads are started or exited, with thread event hooks
+    def threads_changed
+      @lock.synchronize do
+        @active_threads = Thread.list
+        @matched_threads.select! { |thread, _| thread.alive? }
+        @thread_intervals.select! { |thread, _| thread.alive? }
+        refresh_threads_to_scan if @scan_config[:filter]
       end
     end
 
+    # Called in the child process after fork, the extension has reset the scanner already,
+    # as the parent's scanner thread doesn't exist in the child.
+    # The child scans with its own output, %p in the path is its pid.
+    def forked!
+      @scanner_thread = nil
+      @matched_threads = {}
+      @thread_intervals.select! { |thread, _| thread.alive? }
+      @active_threads = Thread.list
+
+      start_scanning if @scan_in_children && @scan_config[:filter]
+    end
+
+    # Kept for servers configured to call it after fork, forked! starts scanning already
     def worker_forked!
-      start_scanning if @scan_config
+      start_scanning if @scan_config[:filter] && !scanning?
     end
 
     private
@@ -105,12 +294,31 @@ module Sdb
       Puma.respond_to?(:cli_config) && Puma.cli_config.options[:workers].to_i > 0
     end
 
+    # @lock must be held, a thread is checked by the filter again until it matches,
+    # as a new thread may not have its name yet
+    def refresh_threads_to_scan
+      filter = @scan_config[:filter]
+      threads_to_scan = @active_threads.select do |thread|
+        next false if thread == @scanner_thread
+
+        @matched_threads[thread] ||= filter.call(thread) ? true : nil
+      end
+      intervals = threads_to_scan.map { |thread| @thread_intervals[thread] }
+
+      self.update_threads_to_scan(threads_to_scan, intervals)
+    end
+
     def start_scanning
-      self.init_logger
+      self.reset_scanner
+      if @aggregation_config
+        self.open_aggregator(@aggregation_config[:path], @aggregation_config[:flush_interval])
+      else
+        self.open_logger(@logger_config)
+      end
+      self.set_record_lines(@scan_config[:record_lines])
 
       @lock.synchronize do
-        threads_to_scan = @active_threads.filter(&@scan_config[:filter]).to_a
-        self.update_threads_to_scan(threads_to_scan)
+        refresh_threads_to_scan
       end
 
       @scanner_thread = Thread.new do
@@ -139,4 +347,15 @@ module ThreadInitializePatch
   end
 end
 
-Thread.prepend(ThreadInitializePatch)
+Thread.prepend(ThreadInitializePatch) unless Sdb.thread_hooks?
+
+module ProcessForkPatch
+  # Process._fork is called by every fork in Ruby, such as Process.fork and Kernel#fork
+  def _fork
+    pid = super
+    Sdb.forked! if pid == 0
+    pid
+  end
+end
+
+Process.singleton_class.prepend(ProcessForkPatch)
@@ -19,15 +19,18 @@ module Sdb
       def handle_request(client, requests)
         t0 = Time.now
         cpu_time0 = CPUTime.time
+        gvl_wait0, = Sdb.gvl_times
         trace_id = client.env['HTTP_TRACE_ID']
         trace_id ||= SecureRandom.hex(16)
 
         Thread.current[:sdb] ||= {}
         Thread.current[:sdb][:trace_id] = trace_id
+        Sdb.begin_request(trace_id)
 
         rv = super
         t1 = Time.now
         cpu_time1 = CPUTime.time
+        gvl_wait1, = Sdb.gvl_times
 
         log = {
           trace_id: trace_id,
@@ -35,6 +38,7 @@ module Sdb
           start_ts: (t0.to_f * 1_000_000).to_i,
           end_ts: (t1.to_f * 1_000_000).to_i,
           cpu_time_ms: (cpu_time1 - cpu_time0) * 1000,
+          gvl_wait_ms: gvl_wait0 && (gvl_wait1 - gvl_wait0) * 1000,
           status: Thread.current[:sdb][:status]
         }
 
@@ -42,6 +46,8 @@ module Sdb
 
         rv
       ensure
+        Sdb.end_request(Thread.current[:sdb]&.dig(:status))
+        Sdb.untag(:endpoint)
         Thread.current[:sdb] = {}
       end
 
@@ -10,8 +10,9 @@ module Sdb
       private
 
       def do_subscribe
+        # samples of the request are tagged with its endpoint, Sdb::PumaPatch untags it
         ActiveSupport::Notifications.subscribe('start_processing.action_controller') do |name, start, finish, id, payload|
-          trace_id = Thread.current[:sdb][:trace_id]
+          trace_id = Thread.current[:sdb]&.dig(:trace_id)
           log = {
             trace_id: trace_id,
             thread_id: Thread.current.native_thread_id,
@@ -20,6 +21,7 @@ module Sdb
             path: payload[:path]
           }
           Sdb.log("[SDB][application][rails]: #{log.to_json}")
+          Sdb.tag(:endpoint, "#{payload[:controller]}##{payload[:action]}")
         end
       end
 
@@ -0,0 +1,77 @@
+// Chrome's trace event format with duration events, one track per native thread.
+// It can be opened by chrome://tracing, Perfetto and speedscope.
+// https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+use crate::symbolizer::FrameTable;
+use crate::timeline::{Event, ThreadTimeline};
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events: Vec<TraceEvent<'a>>,
+    display_time_unit: &'static str,
+}
+
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    name: &'a str,
+    ph: &'static str,
+    ts: u64,
+    pid: u32,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Args<'a>>,
+}
+
+#[derive(Serialize)]
+struct Args<'a> {
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+}
+
+pub fn write<W: Write>(
+    pid: u32,
+    frame_table: &FrameTable,
+    threads: &BTreeMap<u64, ThreadTimeline>,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut trace_events = Vec::new();
+
+    for (thread_id, thread) in threads {
+        for event in &thread.events {
+            let (ph, frame, ts) = match *event {
+                Event::Open { frame, ts } => ("B", frame, ts),
+                Event::Close { frame, ts } => ("E", frame, ts),
+            };
+            let frame = frame_table.get(frame);
+
+            trace_events.push(TraceEvent {
+                name: &frame.label,
+                ph,
+                ts,
+                pid,
+                tid: *thread_id,
+                args: if ph == "B" {
+                    Some(Args {
+                        path: &frame.path,
+                        line: Some(frame.line).filter(|line| *line > 0),
+                    })
+                } else {
+                    None
+                },
+            });
+        }
+    }
+
+    let trace = Trace {
+        trace_events,
+        display_time_unit: "ns",
+    };
+
+    serde_json::to_writer(&mut *out, &trace)?;
+    writeln!(out)
+}
@@ -0,0 +1,59 @@
+// Brendan Gregg's folded stack format, one line per distinct stack:
+//   thread-<native thread id>;<root frame>;...;<current frame> <samples count>
+use crate::symbolizer::{Frame, FrameTable, ResolvedSample};
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Debug, Default)]
+pub struct FoldedStacks {
+    // (thread_id, frames) => samples count
+    counts: BTreeMap<(u64, Vec<usize>), u64>,
+}
+
+impl FoldedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, sample: &ResolvedSample) {
+        *self
+            .counts
+            .entry((sample.thread_id, sample.frames.clone()))
+            .or_insert(0) += 1;
+    }
+
+    pub fn write<W: Write>(&self, frame_table: &FrameTable, out: &mut W) -> io::Result<()> {
+        let mut lines = BTreeMap::new();
+
+        for ((thread_id, frames), count) in &self.counts {
+            let mut line = format!("thread-{}", thread_id);
+            for id in frames {
+                line.push(';');
+                line.push_str(&frame_name(frame_table.get(*id)));
+            }
+
+            // different addresses could have the same frame name
+            *lines.entry(line).or_insert(0) += count;
+        }
+
+        for (line, count) in lines {
+            writeln!(out, "{} {}", line, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+// `;` separates frames and the last space separates the count, so both are replaced
+pub fn frame_name(frame: &Frame) -> String {
+    let name = if frame.path.is_empty() {
+        frame.label.clone()
+    } else if frame.line > 0 {
+        format!("{} ({}:{})", frame.label, frame.path, frame.line)
+    } else {
+        format!("{} ({})", frame.label, frame.path)
+    };
+
+    name.replace(';', ":")
+}
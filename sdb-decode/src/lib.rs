@@ -0,0 +1,8 @@
+pub mod chrome_trace;
+pub mod folded;
+pub mod pprof;
+pub mod reader;
+pub mod requests;
+pub mod speedscope;
+pub mod symbolizer;
+pub mod timeline;
@@ -0,0 +1,183 @@
+use sdb_decode::folded::FoldedStacks;
+use sdb_decode::pprof::PprofBuilder;
+use sdb_decode::reader::{self, ThreadState};
+use sdb_decode::requests::Requests;
+use sdb_decode::symbolizer::{ResolvedSample, Symbolizer};
+use sdb_decode::timeline::Timeline;
+use sdb_decode::{chrome_trace, pprof, speedscope};
+
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "Usage: sdb-decode [--format FORMAT] [--trace TRACE_ID] [--tag KEY=VALUE] [--on-cpu | --waiting-gvl] <sdb-PID.bin | sdb.log>
+
+Decodes samples written by the sdb extension and writes them to stdout.
+--trace only decodes samples of the request marked with TRACE_ID by Sdb.begin_request.
+--tag only decodes samples tagged with KEY=VALUE by Sdb.tag, it can be given more than once.
+--on-cpu only decodes samples of running threads, not the ones waiting for the GVL or blocked.
+         Samples written without thread states, by older versions, are all kept.
+--waiting-gvl only decodes samples of threads waiting for the GVL, where requests lose time to other threads.
+
+Formats:
+  folded      one line per stack (default)
+              thread-<native thread id>;<root frame>;...;<current frame> <samples count>
+  speedscope  speedscope's evented JSON, one profile per thread
+  chrome      Chrome trace event JSON, one track per thread
+  pprof       gzipped profile.proto with samples count, wall time and CPU time
+  requests    one line per request marked by Sdb.begin_request, with the time it waited for the GVL
+              <trace id> thread-<native thread id> <duration micros> <gvl wait micros> <gvl run micros>";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Folded,
+    Speedscope,
+    Chrome,
+    Pprof,
+    Requests,
+}
+
+struct Options {
+    format: Format,
+    trace_id: Option<String>,
+    tags: Vec<(String, String)>,
+    on_cpu: bool,
+    waiting_gvl: bool,
+    path: String,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut format = Format::Folded;
+    let mut trace_id = None;
+    let mut tags = Vec::new();
+    let mut on_cpu = false;
+    let mut waiting_gvl = false;
+    let mut path = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                format = match args.next()?.as_str() {
+                    "folded" => Format::Folded,
+                    "speedscope" => Format::Speedscope,
+                    "chrome" => Format::Chrome,
+                    "pprof" => Format::Pprof,
+                    "requests" => Format::Requests,
+                    _ => return None,
+                }
+            }
+            "-t" | "--trace" => trace_id = Some(args.next()?.clone()),
+            "--tag" => {
+                let (key, value) = args.next()?.split_once('=')?;
+                tags.push((key.to_string(), value.to_string()));
+            }
+            "--on-cpu" => on_cpu = true,
+            "--waiting-gvl" => waiting_gvl = true,
+            "-h" | "--help" => return None,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+
+    Some(Options {
+        format,
+        trace_id,
+        tags,
+        on_cpu,
+        waiting_gvl,
+        path: path?,
+    })
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let (header, records) = reader::open(&options.path)?;
+    let mut symbolizer = Symbolizer::new();
+    let mut folded = FoldedStacks::new();
+    let mut timeline = Timeline::new();
+    let mut pprof_builder = PprofBuilder::new();
+    let mut requests = Requests::new();
+
+    let format = options.format;
+    let trace_id = options.trace_id.as_deref();
+    let tags = &options.tags;
+    let on_cpu = options.on_cpu;
+    let waiting_gvl = options.waiting_gvl;
+    let mut add = |sample: ResolvedSample| {
+        if trace_id.is_some() && sample.trace_id.as_deref() != trace_id {
+            return;
+        }
+
+        if !tags.iter().all(|tag| sample.tags.contains(tag)) {
+            return;
+        }
+
+        if on_cpu && !sample.thread_state.maybe_on_cpu() {
+            return;
+        }
+
+        if waiting_gvl && sample.thread_state != ThreadState::WaitingGvl {
+            return;
+        }
+
+        match format {
+            Format::Folded => folded.add(&sample),
+            Format::Speedscope | Format::Chrome => timeline.add(&sample),
+            Format::Pprof => pprof_builder.add(&sample),
+            Format::Requests => {}
+        }
+    };
+
+    for record in records {
+        let record = record?;
+        if format == Format::Requests {
+            requests.push(&record);
+        }
+        symbolizer.push(record, &mut add);
+    }
+    symbolizer.finish(&mut add);
+
+    if symbolizer.dropped() > 0 {
+        eprintln!(
+            "{} samples were dropped by the extension",
+            symbolizer.dropped()
+        );
+    }
+
+    let pid = header.map(|header| header.pid).unwrap_or(0);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    match format {
+        Format::Folded => folded.write(symbolizer.frame_table(), &mut out)?,
+        Format::Speedscope => speedscope::write(
+            &format!("sdb {}", options.path),
+            symbolizer.frame_table(),
+            &timeline.finish(),
+            &mut out,
+        )?,
+        Format::Chrome => {
+            chrome_trace::write(pid, symbolizer.frame_table(), &timeline.finish(), &mut out)?
+        }
+        Format::Pprof => pprof::write(&pprof_builder.build(symbolizer.frame_table()), &mut out)?,
+        Format::Requests => requests.write(trace_id, &mut out)?,
+    }
+
+    out.flush()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let options = match parse_args(&args) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(&options) {
+        eprintln!("sdb-decode: {}: {}", options.path, e);
+        process::exit(1);
+    }
+}
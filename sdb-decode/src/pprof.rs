@@ -0,0 +1,287 @@
+// pprof's profile.proto, gzipped, with samples count, wall time and CPU time per stack.
+// https://github.com/google/pprof/blob/main/proto/profile.proto
+//
+// A sample's wall time is the time since the previous sample of the same thread, and its CPU time
+// the CPU time the thread used since then, as the extension measured them when it took the sample.
+// Files written before the extension measured them only have timestamps, there a sample is charged
+// the time until the next sample of its thread, and the last sample of a thread the previous interval.
+use crate::symbolizer::{FrameId, FrameTable, ResolvedSample};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(int64, tag = "9")]
+    pub time_nanos: i64,
+    #[prost(int64, tag = "10")]
+    pub duration_nanos: i64,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+    #[prost(int64, tag = "14")]
+    pub default_sample_type: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    // the leaf is at location_id[0]
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+    #[prost(message, repeated, tag = "3")]
+    pub label: Vec<Label>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(int64, tag = "1")]
+    pub key: i64,
+    #[prost(int64, tag = "2")]
+    pub str: i64,
+    #[prost(int64, tag = "3")]
+    pub num: i64,
+    #[prost(int64, tag = "4")]
+    pub num_unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+    #[prost(int64, tag = "2")]
+    pub line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+    #[prost(int64, tag = "3")]
+    pub system_name: i64,
+    #[prost(int64, tag = "4")]
+    pub filename: i64,
+    #[prost(int64, tag = "5")]
+    pub start_line: i64,
+}
+
+#[derive(Debug, Default)]
+struct StackValues {
+    count: i64,
+    wall_nanos: i64,
+    cpu_nanos: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct PprofBuilder {
+    // (thread_id, frames) => values
+    stacks: HashMap<(u64, Vec<FrameId>), StackValues>,
+    // thread_id => (ts, frames) of the previous sample, for files without the times of each sample
+    last_samples: BTreeMap<u64, (u64, Vec<FrameId>)>,
+    // thread_id => wall nanos of the previous interval
+    last_intervals: HashMap<u64, u64>,
+    start_ts: Option<u64>,
+    end_ts: u64,
+}
+
+impl PprofBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A sample is charged the wall and CPU time since its thread's previous sample,
+    // so samples left out by filters or slow request capture don't change the others.
+    // Files without the times of each sample charge a sample the time until the thread's next
+    // sample added, samples of a thread must be added in time order, which is the order in the file.
+    pub fn add(&mut self, sample: &ResolvedSample) {
+        self.start_ts = Some(self.start_ts.map_or(sample.ts, |ts| ts.min(sample.ts)));
+        self.end_ts = self.end_ts.max(sample.ts);
+
+        if let Some(wall_nanos) = sample.wall_nanos {
+            self.add_stack(
+                sample.thread_id,
+                sample.frames.clone(),
+                wall_nanos,
+                sample.cpu_nanos,
+            );
+            return;
+        }
+
+        let previous = self
+            .last_samples
+            .insert(sample.thread_id, (sample.ts, sample.frames.clone()));
+
+        if let Some((ts, frames)) = previous {
+            let interval = sample.ts.saturating_sub(ts) * 1000;
+            self.last_intervals.insert(sample.thread_id, interval);
+            self.add_stack(sample.thread_id, frames, interval, 0);
+        }
+    }
+
+    pub fn build(mut self, frame_table: &FrameTable) -> Profile {
+        for (thread_id, (_, frames)) in std::mem::take(&mut self.last_samples) {
+            let interval = self.last_intervals.get(&thread_id).copied().unwrap_or(0);
+            self.add_stack(thread_id, frames, interval, 0);
+        }
+
+        let mut strings = StringTable::default();
+        let mut profile = Profile {
+            sample_type: vec![
+                ValueType {
+                    r#type: strings.intern("samples"),
+                    unit: strings.intern("count"),
+                },
+                ValueType {
+                    r#type: strings.intern("wall"),
+                    unit: strings.intern("nanoseconds"),
+                },
+                ValueType {
+                    r#type: strings.intern("cpu"),
+                    unit: strings.intern("nanoseconds"),
+                },
+            ],
+            ..Default::default()
+        };
+        profile.period_type = Some(ValueType {
+            r#type: strings.intern("wall"),
+            unit: strings.intern("nanoseconds"),
+        });
+        profile.default_sample_type = strings.intern("wall");
+        let thread_id_key = strings.intern("thread_id");
+
+        // location ids are frame ids + 1, as 0 is reserved.
+        // Frames of the same method at different lines share a function.
+        let mut function_ids = HashMap::new();
+        for (id, frame) in frame_table.frames().iter().enumerate() {
+            let key = (&frame.label, &frame.path, frame.first_lineno);
+            let function_id = match function_ids.get(&key) {
+                Some(function_id) => *function_id,
+                None => {
+                    let function_id = profile.function.len() as u64 + 1;
+                    let name = strings.intern(&frame.label);
+
+                    profile.function.push(Function {
+                        id: function_id,
+                        name,
+                        system_name: name,
+                        filename: strings.intern(&frame.path),
+                        start_line: frame.first_lineno as i64,
+                    });
+                    function_ids.insert(key, function_id);
+                    function_id
+                }
+            };
+
+            let line = if frame.line > 0 {
+                frame.line
+            } else {
+                frame.first_lineno
+            };
+            profile.location.push(Location {
+                id: id as u64 + 1,
+                line: vec![Line {
+                    function_id,
+                    line: line as i64,
+                }],
+            });
+        }
+
+        let mut stacks: Vec<_> = self.stacks.into_iter().collect();
+        stacks.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for ((thread_id, frames), values) in stacks {
+            profile.sample.push(Sample {
+                location_id: frames.iter().rev().map(|id| *id as u64 + 1).collect(),
+                value: vec![values.count, values.wall_nanos, values.cpu_nanos],
+                label: vec![Label {
+                    key: thread_id_key,
+                    num: thread_id as i64,
+                    ..Default::default()
+                }],
+            });
+        }
+
+        if let Some(start_ts) = self.start_ts {
+            profile.time_nanos = start_ts as i64 * 1000;
+            profile.duration_nanos = (self.end_ts - start_ts) as i64 * 1000;
+        }
+
+        profile.string_table = strings.strings;
+        profile
+    }
+
+    fn add_stack(&mut self, thread_id: u64, frames: Vec<FrameId>, wall_nanos: u64, cpu_nanos: u64) {
+        let values = self.stacks.entry((thread_id, frames)).or_default();
+        values.count += 1;
+        values.wall_nanos += wall_nanos as i64;
+        values.cpu_nanos += cpu_nanos as i64;
+    }
+}
+
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl Default for StringTable {
+    // string_table[0] must be ""
+    fn default() -> Self {
+        StringTable {
+            strings: vec!["".to_string()],
+            index: HashMap::from([("".to_string(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.index.get(s) {
+            return *id;
+        }
+
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), id);
+        id
+    }
+}
+
+pub fn write<W: Write>(profile: &Profile, out: &mut W) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(out, Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    encoder.finish()?;
+    Ok(())
+}
@@ -0,0 +1,478 @@
+// Readers for what the sdb extension writes.
+//
+// The binary sample file (sdb-<pid>.bin), see ext/sdb/src/sample_format.rs for the layout,
+// and the legacy fast_log text log (sdb.log) with `[stack_frames]` and `[symbol]` lines.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+pub const MAGIC: &[u8; 8] = b"SDBPROF\0";
+pub const FORMAT_VERSION: u16 = 1;
+
+const RECORD_SAMPLE: u8 = 1;
+const RECORD_SYMBOL: u8 = 2;
+const RECORD_DROPPED: u8 = 3;
+const RECORD_SAMPLE_WITH_PCS: u8 = 4;
+const RECORD_LINE: u8 = 5;
+const RECORD_TRACE: u8 = 6;
+const RECORD_TRACE_END: u8 = 7;
+const RECORD_TAG_SET: u8 = 8;
+
+const SEPARATOR: u64 = u64::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub format_version: u16,
+    pub clock_source: u8,
+    pub pid: u32,
+    pub ruby_version: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub thread_id: u64,
+    pub ts: u64,
+    // from the current frame to the bottom of the stack
+    pub frames: Vec<u64>,
+    // pc of each frame, empty when the extension doesn't record lines
+    pub pcs: Vec<u64>,
+    // the request the thread was serving, 0 when there isn't one
+    pub trace_seq: u64,
+    // the tags of the thread, 0 when it has none
+    pub tag_set_seq: u64,
+    pub thread_state: ThreadState,
+    // the CPU time the thread used since its previous sample, 0 when it isn't known
+    pub cpu_nanos: u64,
+    // the wall time since the thread's previous sample, None in files without it
+    pub wall_nanos: Option<u64>,
+}
+
+// What the thread was doing when it was sampled, Unknown when the extension doesn't record it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Unknown,
+    // on CPU, with the GVL or in a blocking region without it
+    Running,
+    // runnable, but another thread holds the GVL
+    WaitingGvl,
+    // sleeping, waiting for IO or a lock, or blocked in a syscall
+    Blocked,
+}
+
+impl ThreadState {
+    pub fn from_u8(state: u8) -> Self {
+        match state {
+            1 => ThreadState::Running,
+            2 => ThreadState::WaitingGvl,
+            3 => ThreadState::Blocked,
+            _ => ThreadState::Unknown,
+        }
+    }
+
+    // Samples of older files are kept, as it isn't known whether they were on CPU
+    pub fn maybe_on_cpu(&self) -> bool {
+        matches!(self, ThreadState::Running | ThreadState::Unknown)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub iseq_addr: u64,
+    pub label: String,
+    pub path: String,
+    // 0 when the writer doesn't record it
+    pub first_lineno: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub iseq_addr: u64,
+    pub pc: u64,
+    pub lineno: u32,
+}
+
+// A request marked by Sdb.begin_request, trace_seq is unique in the process
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub trace_seq: u64,
+    pub thread_id: u64,
+    pub begin_ts: u64,
+    pub trace_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Sample(Sample),
+    Symbol(Symbol),
+    Line(Line),
+    Dropped(u64),
+    Trace(Trace),
+    // GVL times are 0 when the extension doesn't record them
+    TraceEnd {
+        trace_seq: u64,
+        end_ts: u64,
+        gvl_wait_nanos: u64,
+        gvl_run_nanos: u64,
+    },
+    TagSet {
+        tag_set_seq: u64,
+        tags: Vec<(String, String)>,
+    },
+}
+
+pub type Records = Box<dyn Iterator<Item = io::Result<Record>>>;
+
+// Opens a sample file, the format is detected by the magic.
+// Header is None for legacy text logs.
+pub fn open(path: &str) -> io::Result<(Option<Header>, Records)> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    if reader.fill_buf()?.starts_with(MAGIC) {
+        let reader = BinaryReader::new(reader)?;
+        let header = reader.header().clone();
+        Ok((Some(header), Box::new(reader)))
+    } else {
+        Ok((None, Box::new(TextReader::new(reader))))
+    }
+}
+
+pub struct BinaryReader<R: Read> {
+    reader: R,
+    header: Header,
+    payload: Vec<u8>,
+}
+
+impl<R: Read> BinaryReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a sdb sample file"));
+        }
+
+        let format_version = read_u16(&mut reader)?;
+        if format_version > FORMAT_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported format version {}",
+                format_version
+            )));
+        }
+
+        let mut clock_source = [0; 1];
+        reader.read_exact(&mut clock_source)?;
+        let pid = read_u32(&mut reader)?;
+        let ruby_version = read_str(&mut reader)?;
+
+        Ok(BinaryReader {
+            reader,
+            header: Header {
+                format_version,
+                clock_source: clock_source[0],
+                pid,
+                ruby_version,
+            },
+            payload: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut kind = [0; 1];
+            match self.reader.read_exact(&mut kind) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            // a process killed or still writing leaves the last record truncated,
+            // the records before it are decoded. The payload only grows as it is read,
+            // so a corrupted length doesn't allocate more than the file has.
+            let len = match read_u32(&mut self.reader) {
+                Ok(len) => len as u64,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(truncated_record());
+                }
+                Err(e) => return Err(e),
+            };
+            self.payload.clear();
+            if self
+                .reader
+                .by_ref()
+                .take(len)
+                .read_to_end(&mut self.payload)? as u64
+                != len
+            {
+                return Ok(truncated_record());
+            }
+            let mut payload = &self.payload[..];
+
+            match kind[0] {
+                RECORD_SAMPLE | RECORD_SAMPLE_WITH_PCS => {
+                    let with_pcs = kind[0] == RECORD_SAMPLE_WITH_PCS;
+                    let thread_id = read_u64(&mut payload)?;
+                    let ts = read_u64(&mut payload)?;
+                    let frames_count = read_u32(&mut payload)? as usize;
+                    let mut frames = Vec::with_capacity(frames_count.min(payload.len() / 8));
+                    let mut pcs = Vec::new();
+                    for _ in 0..frames_count {
+                        frames.push(read_u64(&mut payload)?);
+                        if with_pcs {
+                            pcs.push(read_u64(&mut payload)?);
+                        }
+                    }
+                    let trace_seq = if payload.len() >= 8 {
+                        read_u64(&mut payload)?
+                    } else {
+                        0
+                    };
+                    let tag_set_seq = if payload.len() >= 8 {
+                        read_u64(&mut payload)?
+                    } else {
+                        0
+                    };
+                    let thread_state = if !payload.is_empty() {
+                        let state = payload[0];
+                        payload = &payload[1..];
+                        ThreadState::from_u8(state)
+                    } else {
+                        ThreadState::Unknown
+                    };
+                    let cpu_nanos = if payload.len() >= 8 {
+                        read_u64(&mut payload)?
+                    } else {
+                        0
+                    };
+                    let wall_nanos = if payload.len() >= 8 {
+                        Some(read_u64(&mut payload)?)
+                    } else {
+                        None
+                    };
+
+                    return Ok(Some(Record::Sample(Sample {
+                        thread_id,
+                        ts,
+                        frames,
+                        pcs,
+                        trace_seq,
+                        tag_set_seq,
+                        thread_state,
+                        cpu_nanos,
+                        wall_nanos,
+                    })));
+                }
+                RECORD_LINE => {
+                    return Ok(Some(Record::Line(Line {
+                        iseq_addr: read_u64(&mut payload)?,
+                        pc: read_u64(&mut payload)?,
+                        lineno: read_u32(&mut payload)?,
+                    })));
+                }
+                RECORD_SYMBOL => {
+                    let iseq_addr = read_u64(&mut payload)?;
+                    let label = read_str(&mut payload)?;
+                    let path = read_str(&mut payload)?;
+                    let first_lineno = if payload.len() >= 4 {
+                        read_u32(&mut payload)?
+                    } else {
+                        0
+                    };
+
+                    return Ok(Some(Record::Symbol(Symbol {
+                        iseq_addr,
+                        label,
+                        path,
+                        first_lineno,
+                    })));
+                }
+                RECORD_DROPPED => return Ok(Some(Record::Dropped(read_u64(&mut payload)?))),
+                RECORD_TRACE => {
+                    return Ok(Some(Record::Trace(Trace {
+                        trace_seq: read_u64(&mut payload)?,
+                        thread_id: read_u64(&mut payload)?,
+                        begin_ts: read_u64(&mut payload)?,
+                        trace_id: read_str(&mut payload)?,
+                    })));
+                }
+                RECORD_TAG_SET => {
+                    let tag_set_seq = read_u64(&mut payload)?;
+                    let tags_count = read_u32(&mut payload)? as usize;
+                    let mut tags = Vec::with_capacity(tags_count.min(payload.len() / 8));
+                    for _ in 0..tags_count {
+                        tags.push((read_str(&mut payload)?, read_str(&mut payload)?));
+                    }
+
+                    return Ok(Some(Record::TagSet { tag_set_seq, tags }));
+                }
+                RECORD_TRACE_END => {
+                    let trace_seq = read_u64(&mut payload)?;
+                    let end_ts = read_u64(&mut payload)?;
+                    let (gvl_wait_nanos, gvl_run_nanos) = if payload.len() >= 16 {
+                        (read_u64(&mut payload)?, read_u64(&mut payload)?)
+                    } else {
+                        (0, 0)
+                    };
+
+                    return Ok(Some(Record::TraceEnd {
+                        trace_seq,
+                        end_ts,
+                        gvl_wait_nanos,
+                        gvl_run_nanos,
+                    }));
+                }
+                // written by a newer extension, skip it
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+// fast_log lines look like
+//   2024-09-01 10:00:00.000000 INFO sdb::logger - [123][stack_frames][1, 2, ...]
+//   2024-09-01 10:00:00.000000 INFO sdb::stack_scanner - [123][symbol]140000, foo, /app/foo.rb
+// The text format of the extension writes the same lines without the prefix, and `[dropped]3`.
+// A `[stack_frames]` line is a slice of the scanner buffer, samples are separated by
+// two u64::MAX and a sample may continue on the next line.
+pub struct TextReader<R: BufRead> {
+    lines: io::Lines<R>,
+    words: Vec<u64>,
+    pending: std::collections::VecDeque<Record>,
+}
+
+impl<R: BufRead> TextReader<R> {
+    pub fn new(reader: R) -> Self {
+        TextReader {
+            lines: reader.lines(),
+            words: Vec::new(),
+            pending: std::collections::VecDeque::new(),
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> io::Result<()> {
+        if let Some(pos) = line.find("[stack_frames][") {
+            let list = &line[pos + "[stack_frames][".len()..];
+            let list = list.trim_end().trim_end_matches(']');
+
+            for word in list.split(", ").filter(|w| !w.is_empty()) {
+                let word = word
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| invalid_data(&format!("invalid frame {:?}", word)))?;
+                self.words.push(word);
+                self.take_sample();
+            }
+        } else if let Some(pos) = line.find("[symbol]") {
+            let mut parts = line[pos + "[symbol]".len()..].trim_end().splitn(3, ", ");
+            let iseq_addr = parts
+                .next()
+                .and_then(|addr| addr.parse::<u64>().ok())
+                .ok_or_else(|| invalid_data(&format!("invalid symbol line {:?}", line)))?;
+
+            self.pending.push_back(Record::Symbol(Symbol {
+                iseq_addr,
+                label: parts.next().unwrap_or("").to_string(),
+                path: parts.next().unwrap_or("").to_string(),
+                first_lineno: 0,
+            }));
+        } else if let Some(pos) = line.find("[dropped]") {
+            let count = line[pos + "[dropped]".len()..]
+                .trim_end()
+                .parse::<u64>()
+                .map_err(|_| invalid_data(&format!("invalid dropped line {:?}", line)))?;
+            self.pending.push_back(Record::Dropped(count));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn take_sample(&mut self) {
+        let len = self.words.len();
+        if len >= 4 && self.words[len - 1] == SEPARATOR && self.words[len - 2] == SEPARATOR {
+            self.pending.push_back(Record::Sample(Sample {
+                thread_id: self.words[0],
+                ts: self.words[1],
+                frames: self.words[2..len - 2].to_vec(),
+                pcs: Vec::new(),
+                trace_seq: 0,
+                tag_set_seq: 0,
+                thread_state: ThreadState::Unknown,
+                cpu_nanos: 0,
+                wall_nanos: None,
+            }));
+            self.words.clear();
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TextReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(Ok(record));
+            }
+
+            match self.lines.next()? {
+                Ok(line) => {
+                    if let Err(e) = self.parse_line(&line) {
+                        return Some(Err(e));
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// A length longer than what is left is rejected before it is allocated
+fn read_str<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_u32(reader)? as u64;
+    let mut bytes = Vec::new();
+    if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "string longer than its record",
+        ));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid utf8 string"))
+}
+
+#[inline]
+fn truncated_record() -> Option<Record> {
+    eprintln!("sdb-decode: the last record is truncated, it is skipped");
+    None
+}
@@ -0,0 +1,85 @@
+// One line per finished request, in the order they began:
+//   <trace id> thread-<native thread id> <duration micros> <gvl wait micros> <gvl run micros>
+// GVL times are 0 when the extension doesn't record them, before Ruby 3.2.
+use crate::reader::{Record, Trace};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub trace_id: String,
+    pub thread_id: u64,
+    pub begin_ts: u64,
+    pub end_ts: u64,
+    pub gvl_wait_nanos: u64,
+    pub gvl_run_nanos: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Requests {
+    // trace_seq => the request waiting for its trace end record
+    begun: HashMap<u64, Trace>,
+    // trace_seq => request, trace_seq is in the order requests began
+    finished: BTreeMap<u64, Request>,
+}
+
+impl Requests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, record: &Record) {
+        match record {
+            Record::Trace(trace) => {
+                self.begun.insert(trace.trace_seq, trace.clone());
+            }
+            Record::TraceEnd {
+                trace_seq,
+                end_ts,
+                gvl_wait_nanos,
+                gvl_run_nanos,
+            } => {
+                if let Some(trace) = self.begun.remove(trace_seq) {
+                    self.finished.insert(
+                        *trace_seq,
+                        Request {
+                            trace_id: trace.trace_id,
+                            thread_id: trace.thread_id,
+                            begin_ts: trace.begin_ts,
+                            end_ts: *end_ts,
+                            gvl_wait_nanos: *gvl_wait_nanos,
+                            gvl_run_nanos: *gvl_run_nanos,
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Requests without a trace end record, such as ones in progress when the file was written, are left out
+    pub fn finished(&self) -> impl Iterator<Item = &Request> {
+        self.finished.values()
+    }
+
+    pub fn write<W: Write>(&self, trace_id: Option<&str>, out: &mut W) -> io::Result<()> {
+        for request in self.finished() {
+            if trace_id.is_some_and(|trace_id| trace_id != request.trace_id) {
+                continue;
+            }
+
+            writeln!(
+                out,
+                "{} thread-{} {} {} {}",
+                request.trace_id,
+                request.thread_id,
+                request.end_ts.saturating_sub(request.begin_ts),
+                request.gvl_wait_nanos / 1000,
+                request.gvl_run_nanos / 1000
+            )?;
+        }
+
+        Ok(())
+    }
+}
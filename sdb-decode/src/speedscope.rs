@@ -0,0 +1,111 @@
+// speedscope's evented profile, one profile per native thread.
+// https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+use crate::symbolizer::FrameTable;
+use crate::timeline::{Event, ThreadTimeline};
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+const SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+#[derive(Serialize)]
+struct File<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared<'a>,
+    profiles: Vec<Profile>,
+    name: String,
+    exporter: &'static str,
+}
+
+#[derive(Serialize)]
+struct Shared<'a> {
+    frames: Vec<Frame<'a>>,
+}
+
+#[derive(Serialize)]
+struct Frame<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    file: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    #[serde(rename = "type")]
+    profile_type: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: u64,
+    end_value: u64,
+    events: Vec<ProfileEvent>,
+}
+
+#[derive(Serialize)]
+struct ProfileEvent {
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    frame: usize,
+    at: u64,
+}
+
+pub fn write<W: Write>(
+    name: &str,
+    frame_table: &FrameTable,
+    threads: &BTreeMap<u64, ThreadTimeline>,
+    out: &mut W,
+) -> io::Result<()> {
+    let frames = frame_table
+        .frames()
+        .iter()
+        .map(|frame| Frame {
+            name: &frame.label,
+            file: &frame.path,
+            line: [frame.line, frame.first_lineno]
+                .into_iter()
+                .find(|line| *line > 0),
+        })
+        .collect();
+
+    let profiles = threads
+        .iter()
+        .map(|(thread_id, thread)| Profile {
+            profile_type: "evented",
+            name: format!("thread {}", thread_id),
+            unit: "microseconds",
+            start_value: thread.start_ts,
+            end_value: thread.end_ts,
+            events: thread
+                .events
+                .iter()
+                .map(|event| match *event {
+                    Event::Open { frame, ts } => ProfileEvent {
+                        event_type: "O",
+                        frame,
+                        at: ts,
+                    },
+                    Event::Close { frame, ts } => ProfileEvent {
+                        event_type: "C",
+                        frame,
+                        at: ts,
+                    },
+                })
+                .collect(),
+        })
+        .collect();
+
+    let file = File {
+        schema: SCHEMA,
+        shared: Shared { frames },
+        profiles,
+        name: name.to_string(),
+        exporter: concat!("sdb-decode@", env!("CARGO_PKG_VERSION")),
+    };
+
+    serde_json::to_writer(&mut *out, &file)?;
+    writeln!(out)
+}
@@ -0,0 +1,204 @@
+// Resolves iseq addresses in samples to frames.
+//
+// The extension translates the iseqs seen since the previous GC at the beginning of each GC,
+// so the file is a sequence of epochs, each one is samples followed by a batch of symbols.
+// A symbol batch has every iseq seen in its epoch, even ones already in earlier batches, as an
+// address can be reused by another iseq after GC. So a sample is resolved by its own epoch's batch
+// first, which is always right, and only falls back to the latest earlier definition, never to
+// a later one. Lines of (iseq address, pc) pairs are only translated once by the extension,
+// so a line is usually in an earlier batch, and it is looked up in the same order.
+// Samples are tagged with the trace id of their request and the tags of their thread
+// by trace and tag set records, which come before them.
+use crate::reader::{Line, Record, Sample, Symbol, ThreadState};
+
+use std::collections::HashMap;
+
+pub type FrameId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub label: String,
+    pub path: String,
+    pub first_lineno: u32,
+    // the line being executed, 0 when the sample has no pc
+    pub line: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameTable {
+    frames: Vec<Frame>,
+    index: HashMap<Frame, FrameId>,
+}
+
+impl FrameTable {
+    pub fn intern(&mut self, frame: Frame) -> FrameId {
+        if let Some(id) = self.index.get(&frame) {
+            return *id;
+        }
+
+        let id = self.frames.len();
+        self.frames.push(frame.clone());
+        self.index.insert(frame, id);
+        id
+    }
+
+    pub fn get(&self, id: FrameId) -> &Frame {
+        &self.frames[id]
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedSample {
+    pub thread_id: u64,
+    pub ts: u64,
+    // from the bottom of the stack to the current frame, unresolved addresses are skipped
+    pub frames: Vec<FrameId>,
+    // the request the thread was serving
+    pub trace_id: Option<String>,
+    // key and value pairs sorted by key
+    pub tags: Vec<(String, String)>,
+    pub thread_state: ThreadState,
+    // the CPU time the thread used since its previous sample, 0 when it isn't known
+    pub cpu_nanos: u64,
+    // the wall time since the thread's previous sample, None when it isn't known
+    pub wall_nanos: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Symbolizer {
+    frame_table: FrameTable,
+    symbols: HashMap<u64, FrameId>,
+    batch: HashMap<u64, FrameId>,
+    lines: HashMap<(u64, u64), u32>,
+    batch_lines: HashMap<(u64, u64), u32>,
+    // (frame without line, line) => frame
+    line_frames: HashMap<(FrameId, u32), FrameId>,
+    pending: Vec<Sample>,
+    // trace_seq => trace id
+    traces: HashMap<u64, String>,
+    // tag_set_seq => tags
+    tag_sets: HashMap<u64, Vec<(String, String)>>,
+    dropped: u64,
+    unresolved: u64,
+}
+
+impl Symbolizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, record: Record, out: &mut dyn FnMut(ResolvedSample)) {
+        match record {
+            Record::Sample(sample) => {
+                if !self.batch.is_empty() || !self.batch_lines.is_empty() {
+                    self.finish_epoch(out);
+                }
+                self.pending.push(sample);
+            }
+            Record::Symbol(symbol) => self.push_symbol(symbol),
+            Record::Line(line) => self.push_line(line),
+            Record::Dropped(count) => self.dropped += count,
+            Record::Trace(trace) => {
+                self.traces.insert(trace.trace_seq, trace.trace_id);
+            }
+            // samples of the request may still come
+            Record::TraceEnd { .. } => {}
+            Record::TagSet { tag_set_seq, tags } => {
+                self.tag_sets.insert(tag_set_seq, tags);
+            }
+        }
+    }
+
+    // Resolves samples of the last epoch, it should be called after all records are pushed.
+    pub fn finish(&mut self, out: &mut dyn FnMut(ResolvedSample)) {
+        self.finish_epoch(out);
+    }
+
+    pub fn frame_table(&self) -> &FrameTable {
+        &self.frame_table
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn unresolved(&self) -> u64 {
+        self.unresolved
+    }
+
+    fn push_symbol(&mut self, symbol: Symbol) {
+        let id = self.frame_table.intern(Frame {
+            label: symbol.label,
+            path: symbol.path,
+            first_lineno: symbol.first_lineno,
+            line: 0,
+        });
+        self.batch.insert(symbol.iseq_addr, id);
+    }
+
+    fn push_line(&mut self, line: Line) {
+        self.batch_lines
+            .insert((line.iseq_addr, line.pc), line.lineno);
+    }
+
+    fn frame_with_line(&mut self, id: FrameId, line: u32) -> FrameId {
+        if let Some(id) = self.line_frames.get(&(id, line)) {
+            return *id;
+        }
+
+        let mut frame = self.frame_table.get(id).clone();
+        frame.line = line;
+        let line_id = self.frame_table.intern(frame);
+        self.line_frames.insert((id, line), line_id);
+        line_id
+    }
+
+    fn finish_epoch(&mut self, out: &mut dyn FnMut(ResolvedSample)) {
+        for sample in std::mem::take(&mut self.pending) {
+            let mut frames = Vec::with_capacity(sample.frames.len());
+
+            for (i, addr) in sample.frames.iter().enumerate().rev() {
+                let id = match self.batch.get(addr).or_else(|| self.symbols.get(addr)) {
+                    Some(id) => *id,
+                    None => {
+                        self.unresolved += 1;
+                        continue;
+                    }
+                };
+
+                let line = sample.pcs.get(i).and_then(|pc| {
+                    self.batch_lines
+                        .get(&(*addr, *pc))
+                        .or_else(|| self.lines.get(&(*addr, *pc)))
+                });
+
+                match line {
+                    Some(line) if *line > 0 => frames.push(self.frame_with_line(id, *line)),
+                    _ => frames.push(id),
+                }
+            }
+
+            out(ResolvedSample {
+                thread_id: sample.thread_id,
+                ts: sample.ts,
+                frames,
+                trace_id: self.traces.get(&sample.trace_seq).cloned(),
+                tags: self
+                    .tag_sets
+                    .get(&sample.tag_set_seq)
+                    .cloned()
+                    .unwrap_or_default(),
+                thread_state: sample.thread_state,
+                cpu_nanos: sample.cpu_nanos,
+                wall_nanos: sample.wall_nanos,
+            });
+        }
+
+        self.symbols.extend(self.batch.drain());
+        self.lines.extend(self.batch_lines.drain());
+    }
+}
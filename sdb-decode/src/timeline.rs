@@ -0,0 +1,81 @@
+// Turns sampled stacks of a thread into open/close frame events.
+//
+// A stack is assumed to stay the same until the next sample of the same thread,
+// the frames which differ from the previous sample are closed and the new ones are opened.
+use crate::symbolizer::{FrameId, ResolvedSample};
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Open { frame: FrameId, ts: u64 },
+    Close { frame: FrameId, ts: u64 },
+}
+
+#[derive(Debug, Default)]
+pub struct ThreadTimeline {
+    pub start_ts: u64,
+    pub end_ts: u64,
+    pub events: Vec<Event>,
+    stack: Vec<FrameId>,
+}
+
+impl ThreadTimeline {
+    fn add(&mut self, ts: u64, frames: &[FrameId]) {
+        if self.events.is_empty() && self.stack.is_empty() {
+            self.start_ts = ts;
+        }
+        self.end_ts = ts;
+
+        let common = self
+            .stack
+            .iter()
+            .zip(frames)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        while self.stack.len() > common {
+            let frame = self.stack.pop().unwrap();
+            self.events.push(Event::Close { frame, ts });
+        }
+
+        for frame in &frames[common..] {
+            self.stack.push(*frame);
+            self.events.push(Event::Open { frame: *frame, ts });
+        }
+    }
+
+    fn finish(&mut self) {
+        let ts = self.end_ts;
+        while let Some(frame) = self.stack.pop() {
+            self.events.push(Event::Close { frame, ts });
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timeline {
+    threads: BTreeMap<u64, ThreadTimeline>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // samples of a thread must be added in time order, which is the order in the sample file
+    pub fn add(&mut self, sample: &ResolvedSample) {
+        self.threads
+            .entry(sample.thread_id)
+            .or_default()
+            .add(sample.ts, &sample.frames);
+    }
+
+    pub fn finish(mut self) -> BTreeMap<u64, ThreadTimeline> {
+        for thread in self.threads.values_mut() {
+            thread.finish();
+        }
+
+        self.threads
+    }
+}
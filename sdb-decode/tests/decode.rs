@@ -0,0 +1,245 @@
+use sdb_decode::folded::FoldedStacks;
+use sdb_decode::reader::{self, Record, ThreadState};
+use sdb_decode::requests::Requests;
+use sdb_decode::symbolizer::Symbolizer;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn fold(path: &str) -> (String, Symbolizer) {
+    let (_, records) = reader::open(path).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut folded = FoldedStacks::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| folded.add(&sample));
+    }
+    symbolizer.finish(&mut |sample| folded.add(&sample));
+
+    let mut out = Vec::new();
+    folded.write(symbolizer.frame_table(), &mut out).unwrap();
+    (String::from_utf8(out).unwrap(), symbolizer)
+}
+
+#[test]
+fn test_binary_header() {
+    let (header, _) = reader::open(&fixture("sdb-4242.bin")).unwrap();
+    let header = header.unwrap();
+
+    assert_eq!(header.format_version, 1);
+    assert_eq!(header.pid, 4242);
+    assert_eq!(header.ruby_version, "3.3.4");
+}
+
+#[test]
+fn test_binary_to_folded() {
+    let (folded, symbolizer) = fold(&fixture("sdb-4242.bin"));
+
+    // the address of bar is reused by baz after GC
+    assert_eq!(
+        folded,
+        "thread-100;block in <main> (/app/a.rb);foo (/app/a.rb);bar (/app/a.rb) 2\n\
+         thread-100;block in <main> (/app/a.rb);foo (/app/a.rb);baz (/app/b.rb) 1\n\
+         thread-200;block in <main> (/app/a.rb);foo (/app/a.rb) 2\n"
+    );
+    assert_eq!(symbolizer.dropped(), 3);
+    assert_eq!(symbolizer.unresolved(), 1);
+}
+
+#[test]
+fn test_truncated_last_record_is_skipped() {
+    let bytes = std::fs::read(fixture("sdb-4242.bin")).unwrap();
+
+    // the process was killed while writing the last sample
+    let truncated = &bytes[..bytes.len() - 3];
+    let records: Vec<Record> = reader::BinaryReader::new(truncated)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    assert_eq!(records.len(), 9);
+    assert_eq!(
+        records.last(),
+        Some(&Record::Symbol(reader::Symbol {
+            iseq_addr: 4096,
+            label: "baz".to_string(),
+            path: "/app/b.rb".to_string(),
+            first_lineno: 3,
+        }))
+    );
+}
+
+#[test]
+fn test_string_longer_than_its_record_is_rejected() {
+    let bytes = std::fs::read(fixture("sdb-4242.bin")).unwrap();
+    let header_len = 8 + 2 + 1 + 4 + 4 + "3.3.4".len();
+
+    // a symbol record with a corrupted label length of almost 4 GiB
+    let mut corrupted = bytes[..header_len].to_vec();
+    corrupted.push(2);
+    corrupted.extend_from_slice(&12u32.to_le_bytes());
+    corrupted.extend_from_slice(&4096u64.to_le_bytes());
+    corrupted.extend_from_slice(&u32::MAX.to_le_bytes());
+
+    let mut records = reader::BinaryReader::new(&corrupted[..]).unwrap();
+    let err = records.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_text_log_to_folded() {
+    let (header, _) = reader::open(&fixture("sdb.log")).unwrap();
+    assert_eq!(header, None);
+
+    // the thread 200 sample is split across two lines
+    let (folded, _) = fold(&fixture("sdb.log"));
+    assert_eq!(
+        folded,
+        "thread-100;block in <main> (/app/a.rb);foo (/app/a.rb);bar (/app/a.rb) 2\n\
+         thread-200;block in <main> (/app/a.rb);foo (/app/a.rb) 1\n"
+    );
+}
+
+#[test]
+fn test_binary_with_lines_to_folded() {
+    let (folded, _) = fold(&fixture("sdb-lines.bin"));
+
+    assert_eq!(
+        folded,
+        "thread-100;foo (/app/a.rb:2);bar (/app/a.rb:6) 2\n\
+         thread-100;foo (/app/a.rb:2);bar (/app/a.rb:7) 1\n"
+    );
+}
+
+#[test]
+fn test_text_format_to_folded() {
+    let (folded, symbolizer) = fold(&fixture("sdb-text.log"));
+
+    assert_eq!(
+        folded,
+        "thread-100;Foo.call (/app/a.rb);Foo#bar (/app/a.rb) 2\n"
+    );
+    assert_eq!(symbolizer.dropped(), 2);
+}
+
+#[test]
+fn test_samples_tagged_with_traces() {
+    let (_, records) = reader::open(&fixture("sdb-traces.bin")).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut samples = Vec::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| samples.push(sample));
+    }
+    symbolizer.finish(&mut |sample| samples.push(sample));
+
+    // the last sample of req-a comes after its trace end record
+    let trace_ids: Vec<(u64, Option<&str>)> = samples
+        .iter()
+        .map(|sample| (sample.thread_id, sample.trace_id.as_deref()))
+        .collect();
+    assert_eq!(
+        trace_ids,
+        vec![
+            (100, Some("req-a")),
+            (200, None),
+            (100, Some("req-a")),
+            (100, Some("req-b")),
+        ]
+    );
+}
+
+#[test]
+fn test_samples_tagged_with_tag_sets() {
+    let (_, records) = reader::open(&fixture("sdb-tags.bin")).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut samples = Vec::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| samples.push(sample));
+    }
+    symbolizer.finish(&mut |sample| samples.push(sample));
+
+    let tag = |key: &str, value: &str| (key.to_string(), value.to_string());
+    let tags: Vec<Vec<(String, String)>> = samples.into_iter().map(|sample| sample.tags).collect();
+    assert_eq!(
+        tags,
+        vec![
+            vec![tag("endpoint", "Foo#call"), tag("tenant", "1")],
+            vec![tag("tenant", "2")],
+            vec![],
+        ]
+    );
+}
+
+#[test]
+fn test_requests_with_gvl_times() {
+    let (_, records) = reader::open(&fixture("sdb-gvl.bin")).unwrap();
+    let mut requests = Requests::new();
+
+    for record in records {
+        requests.push(&record.unwrap());
+    }
+
+    // req-b is written without GVL times and req-c never ends
+    let finished: Vec<(&str, u64, u64, u64)> = requests
+        .finished()
+        .map(|request| {
+            (
+                request.trace_id.as_str(),
+                request.end_ts - request.begin_ts,
+                request.gvl_wait_nanos,
+                request.gvl_run_nanos,
+            )
+        })
+        .collect();
+    assert_eq!(
+        finished,
+        vec![
+            ("req-a", 250_000, 30_000_000, 120_000_000),
+            ("req-b", 100_000, 0, 0),
+        ]
+    );
+
+    let mut out = Vec::new();
+    requests.write(Some("req-a"), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "req-a thread-100 250000 30000 120000\n"
+    );
+}
+
+#[test]
+fn test_samples_with_thread_states() {
+    let (_, records) = reader::open(&fixture("sdb-states.bin")).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut samples = Vec::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| samples.push(sample));
+    }
+    symbolizer.finish(&mut |sample| samples.push(sample));
+
+    // the last sample is written without a thread state
+    let states: Vec<(u64, ThreadState)> = samples
+        .iter()
+        .map(|sample| (sample.thread_id, sample.thread_state))
+        .collect();
+    assert_eq!(
+        states,
+        vec![
+            (100, ThreadState::Running),
+            (200, ThreadState::WaitingGvl),
+            (300, ThreadState::Blocked),
+            (100, ThreadState::Unknown),
+        ]
+    );
+
+    let on_cpu: Vec<u64> = samples
+        .iter()
+        .filter(|sample| sample.thread_state.maybe_on_cpu())
+        .map(|sample| sample.thread_id)
+        .collect();
+    assert_eq!(on_cpu, vec![100, 100]);
+}
@@ -0,0 +1,274 @@
+use sdb_decode::pprof::{self, PprofBuilder, Profile};
+use sdb_decode::reader::{self, Record, ThreadState};
+use sdb_decode::symbolizer::{ResolvedSample, Symbolizer};
+use sdb_decode::timeline::{ThreadTimeline, Timeline};
+use sdb_decode::{chrome_trace, speedscope};
+
+use flate2::read::GzDecoder;
+use prost::Message;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+fn timeline(name: &str) -> (Symbolizer, BTreeMap<u64, ThreadTimeline>) {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let (_, records) = reader::open(&path).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut timeline = Timeline::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| timeline.add(&sample));
+    }
+    symbolizer.finish(&mut |sample| timeline.add(&sample));
+
+    (symbolizer, timeline.finish())
+}
+
+#[test]
+fn test_speedscope() {
+    let (symbolizer, threads) = timeline("sdb-4242.bin");
+    let mut out = Vec::new();
+    speedscope::write("test", symbolizer.frame_table(), &threads, &mut out).unwrap();
+    let file: Value = serde_json::from_slice(&out).unwrap();
+
+    assert_eq!(
+        file["shared"]["frames"],
+        json!([
+            {"name": "bar", "file": "/app/a.rb", "line": 5},
+            {"name": "foo", "file": "/app/a.rb", "line": 1},
+            {"name": "block in <main>", "file": "/app/a.rb", "line": 9},
+            {"name": "baz", "file": "/app/b.rb", "line": 3},
+        ])
+    );
+
+    let profiles = file["profiles"].as_array().unwrap();
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[0]["name"], "thread 100");
+    assert_eq!(profiles[0]["startValue"], 1700000000000000u64);
+    assert_eq!(profiles[0]["endValue"], 1700000000000200u64);
+
+    // bar is replaced by baz at the third sample, then everything is closed at the end
+    let events: Vec<(String, u64, u64)> = profiles[0]["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["type"].as_str().unwrap().to_string(),
+                e["frame"].as_u64().unwrap(),
+                e["at"].as_u64().unwrap() - 1700000000000000,
+            )
+        })
+        .collect();
+    let expected: Vec<(String, u64, u64)> = vec![
+        ("O", 2, 0),
+        ("O", 1, 0),
+        ("O", 0, 0),
+        ("C", 0, 200),
+        ("O", 3, 200),
+        ("C", 3, 200),
+        ("C", 1, 200),
+        ("C", 2, 200),
+    ]
+    .into_iter()
+    .map(|(t, f, at)| (t.to_string(), f, at))
+    .collect();
+    assert_eq!(events, expected);
+}
+
+#[test]
+fn test_chrome_trace() {
+    let (symbolizer, threads) = timeline("sdb-4242.bin");
+    let mut out = Vec::new();
+    chrome_trace::write(4242, symbolizer.frame_table(), &threads, &mut out).unwrap();
+    let trace: Value = serde_json::from_slice(&out).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+
+    assert_eq!(
+        events[0],
+        json!({
+            "name": "block in <main>",
+            "ph": "B",
+            "ts": 1700000000000000u64,
+            "pid": 4242,
+            "tid": 100,
+            "args": {"path": "/app/a.rb"}
+        })
+    );
+
+    let begins = events.iter().filter(|e| e["ph"] == "B").count();
+    let ends = events.iter().filter(|e| e["ph"] == "E").count();
+    assert_eq!(begins, ends);
+    assert!(events.iter().any(|e| e["tid"] == 200));
+}
+
+#[test]
+fn test_pprof() {
+    let path = format!("{}/tests/fixtures/sdb-4242.bin", env!("CARGO_MANIFEST_DIR"));
+    let (_, records) = reader::open(&path).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut builder = PprofBuilder::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| builder.add(&sample));
+    }
+    symbolizer.finish(&mut |sample| builder.add(&sample));
+
+    let mut out = Vec::new();
+    pprof::write(&builder.build(symbolizer.frame_table()), &mut out).unwrap();
+    let mut bytes = Vec::new();
+    GzDecoder::new(&out[..]).read_to_end(&mut bytes).unwrap();
+    let profile = Profile::decode(&bytes[..]).unwrap();
+
+    let string = |id: i64| profile.string_table[id as usize].as_str();
+    let function_name = |location_id: u64| {
+        let location = profile
+            .location
+            .iter()
+            .find(|l| l.id == location_id)
+            .unwrap();
+        let function = profile
+            .function
+            .iter()
+            .find(|f| f.id == location.line[0].function_id)
+            .unwrap();
+        string(function.name)
+    };
+
+    assert_eq!(string(profile.sample_type[1].r#type), "wall");
+    assert_eq!(profile.function.len(), 4);
+    assert_eq!(profile.function[0].start_line, 5);
+    assert_eq!(profile.duration_nanos, 300_000);
+
+    // thread 100 samples at 0, 100us and 200us, the last one takes the previous interval
+    let stacks: Vec<(Vec<&str>, Vec<i64>)> = profile
+        .sample
+        .iter()
+        .map(|s| {
+            (
+                s.location_id.iter().map(|id| function_name(*id)).collect(),
+                s.value.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        stacks,
+        vec![
+            (vec!["bar", "foo", "block in <main>"], vec![2, 200_000, 0]),
+            (vec!["baz", "foo", "block in <main>"], vec![1, 100_000, 0]),
+            (vec!["foo", "block in <main>"], vec![2, 580_000, 0]),
+        ]
+    );
+}
+
+#[test]
+fn test_pprof_cpu_time() {
+    let path = format!("{}/tests/fixtures/sdb-cpu.bin", env!("CARGO_MANIFEST_DIR"));
+    let (_, records) = reader::open(&path).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut builder = PprofBuilder::new();
+
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut |sample| builder.add(&sample));
+    }
+    symbolizer.finish(&mut |sample| builder.add(&sample));
+
+    let mut out = Vec::new();
+    pprof::write(&builder.build(symbolizer.frame_table()), &mut out).unwrap();
+    let mut bytes = Vec::new();
+    GzDecoder::new(&out[..]).read_to_end(&mut bytes).unwrap();
+    let profile = Profile::decode(&bytes[..]).unwrap();
+
+    let string = |id: i64| profile.string_table[id as usize].as_str();
+    assert_eq!(string(profile.sample_type[2].r#type), "cpu");
+    assert_eq!(string(profile.sample_type[2].unit), "nanoseconds");
+
+    // thread 100 used 80us of CPU in foo and 10us in each sample of bar,
+    // thread 200 is written without CPU time
+    let values: Vec<(i64, Vec<i64>)> = profile
+        .sample
+        .iter()
+        .map(|s| (s.label[0].num, s.value.clone()))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            (100, vec![1, 100_000, 80_000]),
+            (100, vec![2, 200_000, 20_000]),
+            (200, vec![1, 0, 0]),
+        ]
+    );
+}
+
+#[test]
+fn test_pprof_filtered_and_gapped_samples() {
+    let path = format!("{}/tests/fixtures/sdb-gaps.bin", env!("CARGO_MANIFEST_DIR"));
+    let (_, records) = reader::open(&path).unwrap();
+    let mut symbolizer = Symbolizer::new();
+    let mut builder = PprofBuilder::new();
+
+    // like --on-cpu, the blocked sample in baz is left out
+    let mut add = |sample: ResolvedSample| {
+        if sample.thread_state.maybe_on_cpu() {
+            builder.add(&sample);
+        }
+    };
+    for record in records {
+        symbolizer.push(record.unwrap(), &mut add);
+    }
+    symbolizer.finish(&mut add);
+
+    let mut out = Vec::new();
+    pprof::write(&builder.build(symbolizer.frame_table()), &mut out).unwrap();
+    let mut bytes = Vec::new();
+    GzDecoder::new(&out[..]).read_to_end(&mut bytes).unwrap();
+    let profile = Profile::decode(&bytes[..]).unwrap();
+
+    // each sample keeps its own 1ms, neither the blocked sample
+    // nor the half second without samples after it is charged to foo
+    let values: Vec<Vec<i64>> = profile.sample.iter().map(|s| s.value.clone()).collect();
+    assert_eq!(
+        values,
+        vec![vec![1, 1_000_000, 900_000], vec![1, 1_000_000, 800_000]]
+    );
+}
+
+#[test]
+fn test_pprof_samples_measured_without_wall_time() {
+    let mut symbolizer = Symbolizer::new();
+    let mut builder = PprofBuilder::new();
+    let sample = |ts: u64, wall_nanos: u64| {
+        Record::Sample(reader::Sample {
+            thread_id: 100,
+            ts,
+            frames: vec![16],
+            pcs: Vec::new(),
+            trace_seq: 0,
+            tag_set_seq: 0,
+            thread_state: ThreadState::Running,
+            cpu_nanos: 0,
+            wall_nanos: Some(wall_nanos),
+        })
+    };
+
+    // the wall time of both samples is measured as 0, they aren't
+    // charged the 2ms between them like samples of files without it
+    let records = vec![
+        sample(1000, 0),
+        sample(3000, 0),
+        Record::Symbol(reader::Symbol {
+            iseq_addr: 16,
+            label: "<main>".to_string(),
+            path: "/app/a.rb".to_string(),
+            first_lineno: 1,
+        }),
+    ];
+    for record in records {
+        symbolizer.push(record, &mut |sample| builder.add(&sample));
+    }
+    symbolizer.finish(&mut |sample| builder.add(&sample));
+
+    let profile = builder.build(symbolizer.frame_table());
+    let values: Vec<Vec<i64>> = profile.sample.iter().map(|s| s.value.clone()).collect();
+    assert_eq!(values, vec![vec![2, 0, 0]]);
+}
@@ -0,0 +1,356 @@
+// The binary fixtures of the other tests are written by the extension's SampleWriter.
+// Fixtures of older versions of the format are written the same way and then the fields
+// added later are cut off their records, as readers must keep decoding old files.
+// After a format change, rewrite them with
+//   SDB_UPDATE_FIXTURES=1 cargo test -p sdb-decode --test fixtures
+#[allow(dead_code)]
+#[path = "../../ext/sdb/src/sample_format.rs"]
+mod sample_format;
+
+use sample_format::{ClockSource, RecordWriter, SampleHeader, SampleWriter};
+
+use std::io;
+
+// The fields after the frames of a sample, as each version of the writer wrote them
+#[derive(Clone, Copy)]
+enum Trailer {
+    Bare,
+    TraceSeq,
+    TagSetSeq,
+    ThreadState,
+    Full,
+}
+
+impl Trailer {
+    // bytes of the full trailer the version doesn't have
+    fn cut_len(self) -> usize {
+        match self {
+            Trailer::Bare => 33,
+            Trailer::TraceSeq => 25,
+            Trailer::TagSetSeq => 17,
+            Trailer::ThreadState => 16,
+            Trailer::Full => 0,
+        }
+    }
+}
+
+struct FixtureWriter {
+    out: Vec<u8>,
+}
+
+impl FixtureWriter {
+    fn new(pid: u32, ruby_version: &str) -> Self {
+        let mut out = Vec::new();
+        SampleWriter::new(&mut out)
+            .write_header(ClockSource::RealtimeMicros, pid, ruby_version)
+            .unwrap();
+        FixtureWriter { out }
+    }
+
+    // writes a record and cuts cut_len bytes off the end of its payload
+    fn record(
+        &mut self,
+        cut_len: usize,
+        write: impl FnOnce(&mut SampleWriter<&mut Vec<u8>>) -> io::Result<()>,
+    ) -> &mut Self {
+        let mut record = Vec::new();
+        write(&mut SampleWriter::new(&mut record)).unwrap();
+
+        let payload_len = u32::from_le_bytes(record[1..5].try_into().unwrap()) as usize - cut_len;
+        record[1..5].copy_from_slice(&(payload_len as u32).to_le_bytes());
+        record.truncate(5 + payload_len);
+
+        self.out.extend_from_slice(&record);
+        self
+    }
+
+    fn sample(&mut self, header: SampleHeader, frames: &[u64], trailer: Trailer) -> &mut Self {
+        self.record(trailer.cut_len(), |w| w.write_sample(&header, frames))
+    }
+
+    fn sample_with_pcs(
+        &mut self,
+        header: SampleHeader,
+        words: &[u64],
+        trailer: Trailer,
+    ) -> &mut Self {
+        self.record(trailer.cut_len(), |w| {
+            w.write_sample_with_pcs(&header, words)
+        })
+    }
+
+    fn symbol(&mut self, iseq_addr: u64, label: &str, path: &str, first_lineno: u32) -> &mut Self {
+        self.record(0, |w| w.write_symbol(iseq_addr, label, path, first_lineno))
+    }
+
+    fn line(&mut self, iseq_addr: u64, pc: u64, lineno: u32) -> &mut Self {
+        self.record(0, |w| w.write_line(iseq_addr, pc, lineno))
+    }
+
+    fn dropped(&mut self, samples_count: u64) -> &mut Self {
+        self.record(0, |w| w.write_dropped(samples_count))
+    }
+
+    fn trace(
+        &mut self,
+        trace_seq: u64,
+        thread_id: u64,
+        begin_ts: u64,
+        trace_id: &str,
+    ) -> &mut Self {
+        self.record(0, |w| {
+            w.write_trace(trace_seq, thread_id, begin_ts, trace_id)
+        })
+    }
+
+    // gvl is None for the trace end of writers without GVL times
+    fn trace_end(&mut self, trace_seq: u64, end_ts: u64, gvl: Option<(u64, u64)>) -> &mut Self {
+        let (wait, run) = gvl.unwrap_or((0, 0));
+        let cut_len = if gvl.is_some() { 0 } else { 16 };
+        self.record(cut_len, |w| w.write_trace_end(trace_seq, end_ts, wait, run))
+    }
+
+    fn tag_set(&mut self, tag_set_seq: u64, tags: &[(&str, &str)]) -> &mut Self {
+        let tags: Vec<(String, String)> = tags
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        self.record(0, |w| w.write_tag_set(tag_set_seq, &tags))
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+}
+
+fn header(thread_id: u64, ts: u64) -> SampleHeader {
+    SampleHeader {
+        thread_id,
+        ts,
+        trace_seq: 0,
+        tag_set_seq: 0,
+        thread_state: 0,
+        cpu_nanos: 0,
+        wall_nanos: 0,
+    }
+}
+
+fn state_header(thread_id: u64, ts: u64, thread_state: u8) -> SampleHeader {
+    SampleHeader {
+        thread_state,
+        ..header(thread_id, ts)
+    }
+}
+
+fn times_header(
+    thread_id: u64,
+    ts: u64,
+    thread_state: u8,
+    cpu_nanos: u64,
+    wall_nanos: u64,
+) -> SampleHeader {
+    SampleHeader {
+        thread_state,
+        cpu_nanos,
+        wall_nanos,
+        ..header(thread_id, ts)
+    }
+}
+
+fn trace_header(thread_id: u64, ts: u64, trace_seq: u64) -> SampleHeader {
+    SampleHeader {
+        trace_seq,
+        ..header(thread_id, ts)
+    }
+}
+
+fn tag_header(thread_id: u64, ts: u64, tag_set_seq: u64) -> SampleHeader {
+    SampleHeader {
+        tag_set_seq,
+        ..header(thread_id, ts)
+    }
+}
+
+fn build(name: &str) -> Vec<u8> {
+    use Trailer::*;
+
+    match name {
+        // samples of the first version, the address of bar is reused by baz after GC,
+        // and the thread 200 sample after it is never symbolized
+        "sdb-4242.bin" => FixtureWriter::new(4242, "3.3.4")
+            .sample(
+                header(100, 1_700_000_000_000_000),
+                &[4096, 8192, 12288],
+                Bare,
+            )
+            .sample(header(200, 1_700_000_000_000_010), &[8192, 12288], Bare)
+            .sample(
+                header(100, 1_700_000_000_000_100),
+                &[4096, 8192, 12288],
+                Bare,
+            )
+            .symbol(4096, "bar", "/app/a.rb", 5)
+            .symbol(8192, "foo", "/app/a.rb", 1)
+            .symbol(12288, "block in <main>", "/app/a.rb", 9)
+            .dropped(3)
+            .sample(
+                header(100, 1_700_000_000_000_200),
+                &[4096, 8192, 12288, 39321],
+                Bare,
+            )
+            .symbol(4096, "baz", "/app/b.rb", 3)
+            .sample(header(200, 1_700_000_000_000_300), &[8192, 12288], Bare)
+            .finish(),
+        "sdb-lines.bin" => FixtureWriter::new(4343, "3.4.4")
+            .sample_with_pcs(
+                header(100, 1_700_000_000_000_000),
+                &[4096, 1048592, 8192, 2097160],
+                Bare,
+            )
+            .sample_with_pcs(
+                header(100, 1_700_000_000_000_100),
+                &[4096, 1048608, 8192, 2097160],
+                Bare,
+            )
+            .sample_with_pcs(
+                header(100, 1_700_000_000_000_200),
+                &[4096, 1048592, 8192, 2097160],
+                Bare,
+            )
+            .symbol(4096, "bar", "/app/a.rb", 5)
+            .symbol(8192, "foo", "/app/a.rb", 1)
+            .line(4096, 1048592, 6)
+            .line(4096, 1048608, 7)
+            .line(8192, 2097160, 2)
+            .finish(),
+        "sdb-traces.bin" => FixtureWriter::new(4343, "3.3.4")
+            .trace(1, 100, 1_700_000_000_000_000, "req-a")
+            .sample(
+                trace_header(100, 1_700_000_000_000_010, 1),
+                &[8192, 4096],
+                TraceSeq,
+            )
+            .sample(
+                trace_header(200, 1_700_000_000_000_010, 0),
+                &[4096],
+                TraceSeq,
+            )
+            .trace_end(1, 1_700_000_000_000_015, None)
+            .sample(
+                trace_header(100, 1_700_000_000_000_020, 1),
+                &[8192, 4096],
+                TraceSeq,
+            )
+            .trace(2, 100, 1_700_000_000_000_030, "req-b")
+            .sample(
+                trace_header(100, 1_700_000_000_000_040, 2),
+                &[12288, 4096],
+                TraceSeq,
+            )
+            .symbol(4096, "Foo#call", "/app/a.rb", 1)
+            .symbol(8192, "Foo#bar", "/app/a.rb", 5)
+            .symbol(12288, "Foo#baz", "/app/a.rb", 9)
+            .finish(),
+        "sdb-gvl.bin" => FixtureWriter::new(4343, "3.3.0")
+            .trace(1, 100, 1_000_000, "req-a")
+            .trace(2, 200, 1_000_500, "req-b")
+            .sample(trace_header(100, 1_000_100, 1), &[4096], TagSetSeq)
+            .symbol(4096, "work", "app.rb", 1)
+            .trace_end(1, 1_250_000, Some((30_000_000, 120_000_000)))
+            .trace_end(2, 1_100_500, None)
+            .trace(3, 100, 1_300_000, "req-c")
+            .finish(),
+        "sdb-tags.bin" => FixtureWriter::new(4444, "3.3.4")
+            .tag_set(1, &[("endpoint", "Foo#call"), ("tenant", "1")])
+            .sample(
+                tag_header(100, 1_700_000_000_000_010, 1),
+                &[8192, 4096],
+                TagSetSeq,
+            )
+            .tag_set(2, &[("tenant", "2")])
+            .sample(
+                tag_header(200, 1_700_000_000_000_010, 2),
+                &[4096],
+                TagSetSeq,
+            )
+            .sample(
+                tag_header(100, 1_700_000_000_000_020, 0),
+                &[4096],
+                TagSetSeq,
+            )
+            .symbol(4096, "Foo#call", "/app/a.rb", 1)
+            .symbol(8192, "Foo#bar", "/app/a.rb", 5)
+            .finish(),
+        // running, waiting for the GVL and blocked threads, the last sample has no state
+        "sdb-states.bin" => FixtureWriter::new(4343, "3.3.4")
+            .sample(state_header(100, 1000, 1), &[32, 16], ThreadState)
+            .sample(state_header(200, 1000, 2), &[48, 16], ThreadState)
+            .sample(state_header(300, 1000, 3), &[64, 16], ThreadState)
+            .sample(header(100, 2000), &[32, 16], TagSetSeq)
+            .symbol(16, "Foo.call", "/app/a.rb", 1)
+            .symbol(32, "Foo#compute", "/app/a.rb", 5)
+            .symbol(48, "Foo#lock", "/app/a.rb", 9)
+            .symbol(64, "IO#read", "<cfunc>", 0)
+            .finish(),
+        // thread 200 is written without CPU time
+        "sdb-cpu.bin" => FixtureWriter::new(4444, "3.3.4")
+            .sample(times_header(100, 1000, 1, 80_000, 100_000), &[32, 16], Full)
+            .sample(header(200, 1000), &[32, 16], TagSetSeq)
+            .sample(times_header(100, 1100, 1, 10_000, 100_000), &[48, 16], Full)
+            .sample(times_header(100, 1200, 3, 10_000, 100_000), &[48, 16], Full)
+            .symbol(16, "<main>", "/app/a.rb", 1)
+            .symbol(32, "foo", "/app/a.rb", 5)
+            .symbol(48, "bar", "/app/a.rb", 9)
+            .finish(),
+        // thread 100 is blocked in baz between foo and bar, and not sampled for a while after it
+        "sdb-gaps.bin" => FixtureWriter::new(4545, "3.3.4")
+            .sample(
+                times_header(100, 1000, 1, 900_000, 1_000_000),
+                &[32, 16],
+                Full,
+            )
+            .sample(times_header(100, 2000, 3, 0, 1_000_000), &[64, 16], Full)
+            .sample(
+                times_header(100, 500_000, 1, 800_000, 1_000_000),
+                &[48, 16],
+                Full,
+            )
+            .symbol(16, "<main>", "/app/a.rb", 1)
+            .symbol(32, "foo", "/app/a.rb", 5)
+            .symbol(48, "bar", "/app/a.rb", 9)
+            .symbol(64, "baz", "/app/a.rb", 13)
+            .finish(),
+        _ => panic!("unknown fixture {}", name),
+    }
+}
+
+const BINARY_FIXTURES: &[&str] = &[
+    "sdb-4242.bin",
+    "sdb-lines.bin",
+    "sdb-traces.bin",
+    "sdb-gvl.bin",
+    "sdb-tags.bin",
+    "sdb-states.bin",
+    "sdb-cpu.bin",
+    "sdb-gaps.bin",
+];
+
+#[test]
+fn test_fixtures_are_generated() {
+    let update = std::env::var("SDB_UPDATE_FIXTURES").is_ok();
+
+    for name in BINARY_FIXTURES {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let bytes = build(name);
+
+        if update {
+            std::fs::write(&path, &bytes).unwrap();
+        } else {
+            assert!(
+                std::fs::read(&path).unwrap() == bytes,
+                "{} is out of date, rewrite it with SDB_UPDATE_FIXTURES=1",
+                name
+            );
+        }
+    }
+}
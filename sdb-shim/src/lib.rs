@@ -22,7 +22,7 @@ static mut REAL_PTHREAD_COND_SIGNAL: Option<
 
 unsafe fn init_once() {
     INIT.call_once(|| {
-        fast_log::init(Config::new().file("sdb-lock.log").chan_len(Some(1_000_000))).unwrap();
+        fast_log::init(Config::new().file(&log_path()).chan_len(Some(1_000_000))).unwrap();
 
         let lib = Library::new("libpthread.so.0").expect("Failed to load libpthread");
 
@@ -52,6 +52,14 @@ unsafe fn init_once() {
     });
 }
 
+// SDB_LOCK_LOG sets the log's path, %p in it is replaced by the pid,
+// so processes sharing a working directory don't write to the same file.
+fn log_path() -> String {
+    std::env::var("SDB_LOCK_LOG")
+        .unwrap_or("sdb-lock.log".to_string())
+        .replace("%p", &std::process::id().to_string())
+}
+
 fn get_linux_thread_id() -> libc::pid_t {
     unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
 }
@@ -7,6 +7,22 @@ end
 def bar
   sleep 1_000_000
 end
+
+module SdbSpec
+  class Foo
+    def self.call
+      new.call
+    end
+
+    def call
+      run { sleep 1_000_000 }
+    end
+
+    def run
+      yield
+    end
+  end
+end
 RSpec.describe 'RubyVersion' do
   it 'Get execution context from thread' do
     thread = Thread.new { sleep 1_000_000 }
@@ -47,4 +63,54 @@ RSpec.describe 'RubyVersion' do
     expect(SdbTester.iseq_info(iseqs[2])).to eq ['foo', __FILE__]
     expect(SdbTester.iseq_info(iseqs[3])).to eq ['block (3 levels) in <top (required)>', __FILE__]
   end
+
+  it 'Get line from frame pc' do
+    thread = Thread.new { foo }
+    ec = SdbTester.ec_from_thread(thread)
+    sleep 0.1
+    frames = SdbTester.frames_from_ec(ec)
+
+    iseq, pc = frames[1]
+    expect(SdbTester.iseq_info(iseq)).to eq ['bar', __FILE__]
+    expect(SdbTester.lineno(iseq, pc)).to eq 8
+    thread.kill
+  end
+
+  it 'Get cfunc frame info' do
+    thread = Thread.new { foo }
+    ec = SdbTester.ec_from_thread(thread)
+    sleep 0.1
+    iseqs = SdbTester.iseqs_from_ec(ec)
+
+    expect(SdbTester.method_entry_info(iseqs[0])).to eq ['Kernel', 'sleep', false]
+    expect(SdbTester.method_entry_info(iseqs[1])).to eq nil
+    thread.kill
+  end
+
+  it 'Get qualified labels' do
+    thread = Thread.new { SdbSpec::Foo.call }
+    ec = SdbTester.ec_from_thread(thread)
+    sleep 0.1
+    labels = SdbTester.frame_labels(ec)
+
+    expect(labels[0..3]).to eq [
+      'block in SdbSpec::Foo#call', 'SdbSpec::Foo#run', 'SdbSpec::Foo#call', 'SdbSpec::Foo.call'
+    ]
+    thread.kill
+  end
+
+  it 'Get the GVL of the thread' do
+    thread = Thread.new { sleep 1_000_000 }
+    sleep 0.1
+    lock_addr, owner, current = SdbTester.gvl_info(Thread.current)
+    thread_lock_addr, thread_owner, thread_addr = SdbTester.gvl_info(thread)
+
+    # threads of a ractor share the GVL, which the current thread holds
+    expect(lock_addr).to be > 0
+    expect(thread_lock_addr).to eq lock_addr
+    expect(owner).to eq current
+    expect(thread_owner).to eq current
+    expect(thread_addr).not_to eq current
+    thread.kill
+  end
 end
@@ -0,0 +1,262 @@
+# frozen_string_literal: true
+
+RSpec.describe 'Sdb' do
+  after do
+    Sdb.stop
+    Sdb.init_logger
+    Sdb.aggregation_mode(false)
+    Sdb.slow_request_mode(false)
+    Dir.glob("sdb-#{Process.pid}*.{bin,txt,folded}").each { |path| File.delete(path) }
+  end
+
+  it 'Starts scanning again after stopped' do
+    Sdb.scan_all_threads(0.001)
+    expect(Sdb.scanning?).to eq true
+
+    Sdb.pause
+    Sdb.resume
+    Sdb.set_interval(0.002)
+
+    expect(Sdb.stop).to eq true
+    expect(Sdb.scanning?).to eq false
+    expect(Sdb.stop).to eq false
+
+    expect(Sdb.start).to eq true
+    expect(Sdb.start).to eq false
+    expect(Sdb.stop).to eq true
+
+    expect(Dir.glob("sdb-#{Process.pid}*.bin").count).to eq 2
+  end
+
+  it 'Profiles all threads for a window' do
+    expect(Sdb.profile_window(0.05)).to eq true
+    expect(Sdb.scanning?).to eq false
+    expect(Dir.glob("sdb-#{Process.pid}-*.bin").count).to eq 1
+  end
+
+  it 'Writes samples of a window in aggregation mode' do
+    Sdb.aggregation_mode(flush_interval: 0)
+
+    expect(Sdb.profile_window(0.05)).to eq true
+    expect(Dir.glob("sdb-#{Process.pid}-*.bin").count).to eq 1
+    expect(File.exist?("sdb-#{Process.pid}.folded")).to eq false
+  end
+
+  it 'Writes samples to the configured path and format' do
+    Sdb.init_logger(path: 'sdb-%p-text.txt', format: :text)
+    Sdb.scan_all_threads(0.001)
+    sleep 0.05
+    Sdb.stop
+
+    expect(File.read("sdb-#{Process.pid}-text.txt")).to include('[stack_frames]')
+  end
+
+  it 'Aggregates samples into folded stacks' do
+    Sdb.aggregation_mode(flush_interval: 0)
+    Sdb.scan_all_threads(0.001)
+    sleep 0.05
+    Sdb.stop
+
+    lines = File.readlines("sdb-#{Process.pid}.folded")
+    expect(lines).not_to be_empty
+    expect(lines).to all(match(/\Athread-\d+.* \d+ \d+ \d+\n\z/))
+    expect(Dir.glob("sdb-#{Process.pid}*.bin")).to be_empty
+  end
+
+  it 'Flushes folded stacks without GC' do
+    Sdb.aggregation_mode(flush_interval: 0)
+    GC.disable
+    Sdb.scan_all_threads(0.001)
+    sleep 1.5
+
+    expect(File.exist?("sdb-#{Process.pid}.folded")).to eq true
+  ensure
+    GC.enable
+  end
+
+  def sdb_spec_samples(records, key, seq)
+    records.select { |record| record[:kind] == :sample && record[key] == seq }
+  end
+
+  it 'Tags samples with the trace id of the request' do
+    Sdb.scan_all_threads(0.001)
+    thread = Thread.new do
+      Sdb.begin_request('sdb-spec-trace')
+      SampleFile.wait_for do |records|
+        trace = records.find { |record| record[:kind] == :trace && record[:trace_id] == 'sdb-spec-trace' }
+        trace && sdb_spec_samples(records, :trace_seq, trace[:trace_seq]).any?
+      end
+      Sdb.end_request
+    end
+    thread.join
+    Sdb.stop
+
+    records = SampleFile.records
+    trace = records.find { |record| record[:kind] == :trace && record[:trace_id] == 'sdb-spec-trace' }
+    samples = sdb_spec_samples(records, :trace_seq, trace[:trace_seq])
+    expect(samples.map { |sample| sample[:thread_id] }.uniq).to eq [trace[:thread_id]]
+    expect(records.index(trace)).to be < records.index(samples.first)
+    expect(records).to include(a_hash_including(kind: :trace_end, trace_seq: trace[:trace_seq]))
+  end
+
+  it 'Tags samples with the tags of the thread' do
+    Sdb.scan_all_threads(0.001)
+    thread = Thread.new do
+      Sdb.tag(:sdb_spec_tenant, 42)
+      SampleFile.wait_for do |records|
+        tag_set = records.find { |record| record[:kind] == :tag_set && record[:tags] == { 'sdb_spec_tenant' => '42' } }
+        tag_set && sdb_spec_samples(records, :tag_set_seq, tag_set[:tag_set_seq]).any?
+      end
+      Sdb.untag(:sdb_spec_tenant)
+    end
+    thread.join
+    Sdb.stop
+
+    records = SampleFile.records
+    tag_set = records.find { |record| record[:kind] == :tag_set && record[:tags] == { 'sdb_spec_tenant' => '42' } }
+    samples = sdb_spec_samples(records, :tag_set_seq, tag_set[:tag_set_seq])
+    expect(samples.map { |sample| sample[:thread_id] }.uniq.count).to eq 1
+    expect(records.index(tag_set)).to be < records.index(samples.first)
+  end
+
+  it 'Only writes samples of slow or failed requests' do
+    Sdb.slow_request_mode(threshold: 0.03)
+    Sdb.scan_all_threads(0.001)
+    thread = Thread.new do
+      Sdb.begin_request('sdb-spec-fast')
+      Sdb.end_request(200)
+
+      # the request is slow by its duration, it runs until the threshold has passed
+      Sdb.begin_request('sdb-spec-slow')
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      Thread.pass while Process.clock_gettime(Process::CLOCK_MONOTONIC) - started < 0.05
+      Sdb.end_request(200)
+
+      Sdb.begin_request('sdb-spec-failed')
+      Sdb.end_request(503)
+    end
+    thread.join
+    Sdb.stop
+
+    records = SampleFile.records
+    trace_ids = records.select { |record| record[:kind] == :trace }.map { |record| record[:trace_id] }
+    expect(trace_ids).to eq %w[sdb-spec-slow sdb-spec-failed]
+
+    slow = records.find { |record| record[:kind] == :trace && record[:trace_id] == 'sdb-spec-slow' }
+    expect(sdb_spec_samples(records, :trace_seq, slow[:trace_seq])).not_to be_empty
+  end
+
+  def sdb_spec_before_gc
+    10_000.times { |i| i * i }
+  end
+
+  # the symbols of the epoch before GC are written before the request is kept,
+  # its samples from that epoch have snapshot ids with their own symbols after its trace record
+  def sdb_spec_snapshot_after_trace?(records)
+    records.each_with_index.any? do |trace, i|
+      next false unless trace[:kind] == :trace && trace[:trace_id].start_with?('sdb-spec-gc')
+
+      later = records[i + 1..]
+      ids = sdb_spec_samples(later, :trace_seq, trace[:trace_seq])
+            .flat_map { |sample| sample[:frames] }
+            .select { |frame| frame & SampleFile::SNAPSHOT_ID_BIT != 0 }
+      later.any? do |record|
+        record[:kind] == :symbol && ids.include?(record[:iseq_addr]) && record[:label] == 'sdb_spec_before_gc'
+      end
+    end
+  end
+
+  it 'Writes symbols of captured samples taken before GC with the request' do
+    Sdb.slow_request_mode(threshold: 60)
+    Sdb.scan_all_threads(0.001)
+
+    # failed requests are kept, one is made until a sample of it is taken before GC
+    thread = Thread.new do
+      attempt = 0
+      SampleFile.wait_for do |records|
+        next true if sdb_spec_snapshot_after_trace?(records)
+
+        attempt += 1
+        Sdb.begin_request("sdb-spec-gc-#{attempt}")
+        sdb_spec_before_gc
+        GC.start
+        Sdb.end_request(500)
+        false
+      end
+    end
+    thread.join
+    Sdb.stop
+
+    expect(sdb_spec_snapshot_after_trace?(SampleFile.records)).to eq true
+  end
+
+  it 'Finds threads by thread event hooks since Ruby 3.2' do
+    expect(Sdb.thread_hooks?).to eq(Gem::Version.new(RUBY_VERSION) >= Gem::Version.new('3.2.0'))
+
+    thread = Thread.new { sleep 0.05 }
+    sleep 0.01
+    Sdb.threads_changed if Sdb.thread_hooks?
+    expect(Sdb.instance_variable_get(:@active_threads)).to include(thread)
+    thread.join
+  end
+
+  it 'Keeps scanning while threads exit' do
+    Sdb.scan_all_threads(0.001)
+    20.times do
+      threads = 5.times.map { Thread.new { sleep rand(0.01) } }
+      threads.each(&:join)
+      GC.start
+    end
+
+    expect(Sdb.scanning?).to eq true
+    expect(Sdb.stop).to eq true
+  end
+
+  it 'Measures GVL wait and run time of the thread' do
+    if Sdb.thread_hooks?
+      wait0, run0 = Sdb.gvl_times
+      threads = 2.times.map { Thread.new { 100_000.times { |i| i * i } } }
+      threads.each(&:join)
+      wait1, run1 = Sdb.gvl_times
+
+      expect(wait1).to be >= wait0
+      expect(run1).to be > run0
+    else
+      expect(Sdb.gvl_times).to be_nil
+    end
+  end
+
+  it 'Scans again in a forked child' do
+    Sdb.scan_all_threads(0.001)
+    pid = fork do
+      sleep 0.05
+      exit!(Sdb.scanning? && Sdb.stop ? 0 : 1)
+    end
+    _, status = Process.wait2(pid)
+    Sdb.stop
+
+    child_files = Dir.glob("sdb-#{pid}*.bin")
+    expect(status.exitstatus).to eq 0
+    expect(child_files.count).to eq 1
+  ensure
+    child_files&.each { |path| File.delete(path) }
+  end
+
+  it 'Rotates the sample file without GC' do
+    Sdb.continuous_mode(max_bytes: 1, max_age: 3600, keep: 10)
+    GC.disable
+    Sdb.scan_all_threads(0.001)
+    sleep 2.5
+    Sdb.stop
+
+    expect(Dir.glob("sdb-#{Process.pid}-*.bin").count).to be > 1
+  ensure
+    GC.enable
+    Sdb.set_rotation(nil, 0, 0)
+  end
+
+  it 'Rejects an unknown sink' do
+    expect { Sdb.init_logger(sink: :tcp) }.to raise_error(ArgumentError)
+    expect { Sdb.init_logger(sink: :unix) }.to raise_error(ArgumentError)
+  end
+end
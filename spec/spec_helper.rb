@@ -3,6 +3,8 @@
 require "sdb"
 require "byebug"
 
+require_relative "support/sample_file"
+
 RSpec.configure do |config|
   # Enable flags like --only-failures and --next-failure
   config.example_status_persistence_file_path = ".rspec_status"
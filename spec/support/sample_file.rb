@@ -0,0 +1,81 @@
+# frozen_string_literal: true
+
+require "stringio"
+
+# Reads the records of binary sample files, see ext/sdb/src/sample_format.rs for the layout.
+# A record still being written at the end of a file is left out.
+module SampleFile
+  MAGIC = "SDBPROF\0".b
+  # frame addresses of captured samples taken before an earlier epoch end
+  SNAPSHOT_ID_BIT = 1 << 63
+
+  module_function
+
+  def records(pattern = "sdb-#{Process.pid}*.bin")
+    Dir.glob(pattern).sort.flat_map { |path| read(path) }
+  end
+
+  def read(path)
+    io = StringIO.new(File.binread(path))
+    return [] unless io.read(8) == MAGIC
+
+    io.read(2 + 1 + 4)
+    read_str(io)
+
+    records = []
+    until io.eof?
+      kind, len = io.read(5)&.unpack("CV")
+      break if len.nil?
+
+      payload = io.read(len)
+      break if payload.nil? || payload.bytesize < len
+
+      record = parse(kind, StringIO.new(payload))
+      records << record if record
+    end
+    records
+  end
+
+  def parse(kind, payload)
+    case kind
+    when 1, 4
+      thread_id, ts, frames_count = payload.read(20).unpack("Q<Q<V")
+      words = payload.read(frames_count * (kind == 4 ? 16 : 8)).unpack("Q<*")
+      frames = kind == 4 ? words.each_slice(2).map(&:first) : words
+      trace_seq, tag_set_seq = payload.read(16).unpack("Q<Q<")
+      { kind: :sample, thread_id: thread_id, ts: ts, frames: frames, trace_seq: trace_seq, tag_set_seq: tag_set_seq }
+    when 2
+      iseq_addr = payload.read(8).unpack1("Q<")
+      { kind: :symbol, iseq_addr: iseq_addr, label: read_str(payload), path: read_str(payload) }
+    when 6
+      trace_seq, thread_id, begin_ts = payload.read(24).unpack("Q<Q<Q<")
+      { kind: :trace, trace_seq: trace_seq, thread_id: thread_id, begin_ts: begin_ts, trace_id: read_str(payload) }
+    when 7
+      trace_seq, end_ts = payload.read(16).unpack("Q<Q<")
+      { kind: :trace_end, trace_seq: trace_seq, end_ts: end_ts }
+    when 8
+      tag_set_seq, tags_count = payload.read(12).unpack("Q<V")
+      tags = tags_count.times.to_h { [read_str(payload), read_str(payload)] }
+      { kind: :tag_set, tag_set_seq: tag_set_seq, tags: tags }
+    end
+  end
+
+  def read_str(io)
+    len = io.read(4).unpack1("V")
+    io.read(len).force_encoding(Encoding::UTF_8)
+  end
+
+  # Ends epochs by GC until the records written so far satisfy the block, so samples taken by the
+  # scanner are waited for instead of slept for. The writer flushes the file at each epoch end.
+  def wait_for(timeout: 5)
+    deadline = Process.clock_gettime(Process::CLOCK_MONOTONIC) + timeout
+    loop do
+      GC.start
+      records = self.records
+      return records if yield(records)
+      raise "timed out waiting for sample records" if Process.clock_gettime(Process::CLOCK_MONOTONIC) > deadline
+
+      Thread.pass
+    end
+  end
+end